@@ -0,0 +1,207 @@
+//! Latency of a reliable broadcast, of a round and of a whole consensus run, at several process
+//! counts.
+//!
+//! Broadcasts are run both on threads, as deployed, and as their state machine driven in memory,
+//! which leaves the scheduling out. Rounds only run in memory, threads running whole consensus
+//! instances rather than single rounds. Consensus runs go through the simulation harness on
+//! either runtime. Broadcasts and rounds report their messages as throughput, and every benchmark
+//! prints the messages it takes per delivery, round or decision before being measured:
+//!
+//! ```text
+//! cargo bench --bench consensus -- consensus/simulator
+//! ```
+
+use std::{collections::VecDeque, hint::black_box, sync::Arc, thread, time::Duration};
+
+use async_byz_consensus::{
+    broadcast::{self, BroadcastSender, BroadcastState, BroadcastValue},
+    messaging::{Message, MessageType},
+    network::{self, ChannelConfig},
+    quorum::{QuorumSystem, ThresholdQuorum},
+    round::RoundState,
+    simulation::{self, Coin, InitialValues, NetworkConditions, Runtime, SimulationConfig},
+    util::CancellationToken,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const PROCESS_COUNTS: [usize; 4] = [4, 16, 64, 100];
+
+/// Time a thread run gets before its undecided processes are given up on.
+const RUN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Delivers every message to every process in the order sent until none is left, starting from
+/// `initial`, and returns how many messages were sent.
+fn run_in_memory<S>(
+    states: &mut [S],
+    initial: Vec<Message<bool>>,
+    mut handle: impl FnMut(&mut S, Message<bool>) -> Vec<Message<bool>>,
+) -> usize {
+    let mut sent = initial.len();
+    let mut in_flight = VecDeque::from(initial);
+    while let Some(message) = in_flight.pop_front() {
+        for state in states.iter_mut() {
+            let outgoing = handle(state, message.clone());
+            sent += outgoing.len();
+            in_flight.extend(outgoing);
+        }
+    }
+    sent
+}
+
+/// A broadcast from process 0 delivered by every process, returning the messages sent.
+fn broadcast_in_memory(process_count: usize) -> usize {
+    let quorum = ThresholdQuorum::new(process_count);
+    let mut states: Vec<_> = (0..process_count).map(BroadcastState::new).collect();
+    let value = BroadcastValue::new(true, false);
+    let initiate = Message::new(0, 0, 0, value, MessageType::Initiate);
+    let sent = run_in_memory(&mut states, vec![initiate], |state, message| {
+        state.handle_message(&quorum, message)
+    });
+    assert!(states.iter().all(|state| state.delivered().is_some()));
+    sent
+}
+
+/// The same broadcast with a thread per process, as in `broadcast_protocol`.
+fn broadcast_on_threads(process_count: usize) {
+    let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(process_count));
+    let endpoints = network::connect(process_count, ChannelConfig::for_cluster(process_count));
+    let cancel = CancellationToken::new();
+    thread::scope(|scope| {
+        let workers: Vec<_> = endpoints
+            .into_iter()
+            .enumerate()
+            .map(|(id, (senders, inbox))| {
+                let (quorum, cancel) = (quorum.clone(), &cancel);
+                scope.spawn(move || {
+                    let sender = BroadcastSender::new(id, senders);
+                    match id {
+                        0 => {
+                            let value = BroadcastValue::new(true, false);
+                            broadcast::local_broadcast(0, value, quorum, inbox, sender, cancel)
+                        }
+                        _ => broadcast::broadcast_protocol(quorum, inbox, sender, cancel),
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert!(worker.join().unwrap().is_some());
+        }
+    });
+}
+
+/// A round in which every process broadcasts, until every broadcast is delivered and validated,
+/// returning the messages sent.
+fn round_in_memory(process_count: usize) -> usize {
+    let quorum = ThresholdQuorum::new(process_count);
+    let mut states: Vec<_> = (0..process_count)
+        .map(|id| RoundState::new(id, 0))
+        .collect();
+    // Processes split between both values
+    let initiates = states
+        .iter()
+        .enumerate()
+        .map(|(id, state)| state.initiate(BroadcastValue::new(id % 2 == 0, false)))
+        .collect();
+    let sent = run_in_memory(&mut states, initiates, |state, message| {
+        let outgoing = state.handle_message(&quorum, message);
+        state.validate_pending(&quorum, None);
+        outgoing
+    });
+    assert!(states.iter().all(|state| state.is_complete(&quorum)));
+    sent
+}
+
+fn config(process_count: usize, runtime: Runtime) -> SimulationConfig {
+    SimulationConfig {
+        process_count,
+        faults: Vec::new(),
+        initial_values: InitialValues::Split,
+        coin: Coin::Common,
+        runtime,
+        seed: 0,
+        timeout: RUN_TIMEOUT,
+        network: NetworkConditions::default(),
+    }
+}
+
+/// Messages sent per decision in a run, as the sweep subcommand counts them.
+fn messages_per_decision(config: &SimulationConfig) -> f64 {
+    let report = simulation::run(config);
+    let decisions = report.nodes.iter().filter(|node| node.decided.is_some());
+    let messages: usize = report.nodes.iter().map(|node| node.messages_sent).sum();
+    messages as f64 / decisions.count().max(1) as f64
+}
+
+fn broadcast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast");
+    for process_count in PROCESS_COUNTS {
+        let messages = broadcast_in_memory(process_count);
+        println!("broadcast/{process_count}: {messages} messages per delivery by all");
+        group.throughput(Throughput::Elements(messages as u64));
+        group.bench_with_input(
+            BenchmarkId::new("in_memory", process_count),
+            &process_count,
+            |b, count| b.iter(|| broadcast_in_memory(black_box(*count))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("threads", process_count),
+            &process_count,
+            |b, count| b.iter(|| broadcast_on_threads(black_box(*count))),
+        );
+    }
+    group.finish();
+}
+
+fn round(c: &mut Criterion) {
+    let mut group = c.benchmark_group("round");
+    for process_count in PROCESS_COUNTS {
+        let messages = round_in_memory(process_count);
+        println!("round/{process_count}: {messages} messages per round");
+        group.throughput(Throughput::Elements(messages as u64));
+        group.bench_with_input(
+            BenchmarkId::new("in_memory", process_count),
+            &process_count,
+            |b, count| b.iter(|| round_in_memory(black_box(*count))),
+        );
+    }
+    group.finish();
+}
+
+fn consensus(c: &mut Criterion) {
+    let mut group = c.benchmark_group("consensus");
+    for (name, runtime) in [
+        ("simulator", Runtime::Simulator),
+        ("threads", Runtime::Threads),
+    ] {
+        for process_count in PROCESS_COUNTS {
+            let config = config(process_count, runtime);
+            // Counted inside the benchmark, so that thread runs filtered out are not run at all
+            let mut counted = false;
+            group.bench_with_input(
+                BenchmarkId::new(name, process_count),
+                &config,
+                |b, config| {
+                    if !counted {
+                        let per_decision = messages_per_decision(config);
+                        println!("consensus/{name}/{process_count}: {per_decision:.1} messages per decision");
+                        counted = true;
+                    }
+                    b.iter(|| {
+                        let report = simulation::run(black_box(config));
+                        assert!(report.agreement().is_some(), "{report}");
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // Whole runs take long enough for a few samples to be telling
+    config = Criterion::default().sample_size(10);
+    targets = broadcast, round, consensus
+}
+criterion_main!(benches);
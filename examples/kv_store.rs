@@ -0,0 +1,321 @@
+//! Replicated key-value store with a command-line client.
+//!
+//! Starts a local cluster of replicas, the last ones running `faulty_process`, and reads
+//! commands from standard input, one per line:
+//!
+//! ```text
+//! put <key> <value>
+//! get <key>
+//! cas <key> <expected value, or - if absent> <new value>
+//! ```
+//!
+//! Each response is printed once a blocking set of replicas agrees on it. At the end of the
+//! input, the honest replicas are stopped and their states compared.
+//!
+//! ```text
+//! printf 'put a 1\ncas a 1 2\nget a\n' | cargo run --example kv_store -- --replicas 7
+//! ```
+//!
+//! With `--metrics <port>`, the metrics of the honest replicas are served to Prometheus at
+//! `http://127.0.0.1:<port>/metrics` for as long as the input lasts.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    io::{self, BufRead},
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_byz_consensus::{
+    broadcast::BroadcastValue,
+    byz_protocol,
+    cluster::ClusterHandle,
+    faulty,
+    metrics::{self, Registry},
+    network::{self, ChannelConfig},
+    quorum::{QuorumSystem, ThresholdQuorum},
+    smr::{Application, Client, ClientKeys, Reply, Request},
+    util::{self, NetworkInfo},
+};
+use crossbeam::channel::{self, Receiver, Sender};
+use ed25519_dalek::SigningKey;
+use rand::Rng;
+use serde::Serialize;
+
+/// Time the replicas get to answer a request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time the replicas get to stop once the input is over.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+enum Command {
+    Put {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    /// Sets `key` to `new` if its value is `expected`, `None` standing for no value.
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: String,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+enum Response {
+    /// Value of the key before the command.
+    Value(Option<String>),
+    Swapped(bool),
+}
+
+#[derive(Debug, Default)]
+struct KvStore {
+    entries: BTreeMap<String, String>,
+}
+
+impl Application for KvStore {
+    type Command = Command;
+    type Response = Response;
+    type Snapshot = BTreeMap<String, String>;
+
+    fn apply(&mut self, command: Command) -> Response {
+        match command {
+            Command::Put { key, value } => Response::Value(self.entries.insert(key, value)),
+            Command::Get { key } => Response::Value(self.entries.get(&key).cloned()),
+            Command::Cas { key, expected, new } => {
+                let swapped = self.entries.get(&key) == expected.as_ref();
+                if swapped {
+                    self.entries.insert(key, new);
+                }
+                Response::Swapped(swapped)
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.entries.clone()
+    }
+
+    fn restore(&mut self, snapshot: Self::Snapshot) {
+        self.entries = snapshot;
+    }
+}
+
+struct Options {
+    replicas: usize,
+    faulty: usize,
+    window: usize,
+    metrics_port: Option<u16>,
+}
+
+const USAGE: &str =
+    "Usage: kv_store [--replicas <count>] [--faulty <count>] [--window <slots>] [--metrics <port>]";
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        replicas: 4,
+        faulty: usize::MAX,
+        window: 4,
+        metrics_port: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or(format!("Missing value for {flag}"))?;
+        let value: usize = value
+            .parse()
+            .map_err(|_| format!("Invalid value for {flag}: {value}"))?;
+        match flag.as_str() {
+            "--replicas" => options.replicas = value,
+            "--faulty" => options.faulty = value,
+            "--window" => options.window = value,
+            "--metrics" => {
+                let port = u16::try_from(value).map_err(|_| format!("Invalid port: {value}"))?;
+                options.metrics_port = Some(port);
+            }
+            _ => return Err(format!("Unknown option {flag}")),
+        }
+    }
+    if options.faulty == usize::MAX {
+        options.faulty = util::faulty_count(options.replicas);
+    }
+    if options.replicas == 0 || options.window == 0 {
+        return Err("Expected at least one replica and one slot".to_string());
+    }
+    if options.faulty > util::faulty_count(options.replicas) {
+        return Err(format!(
+            "At most {} of {} replicas can be faulty",
+            util::faulty_count(options.replicas),
+            options.replicas
+        ));
+    }
+    Ok(options)
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<_> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["put", key, value] => Ok(Command::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+        ["get", key] => Ok(Command::Get {
+            key: key.to_string(),
+        }),
+        ["cas", key, expected, new] => Ok(Command::Cas {
+            key: key.to_string(),
+            expected: (*expected != "-").then(|| expected.to_string()),
+            new: new.to_string(),
+        }),
+        _ => Err(format!("Unknown command: {line}")),
+    }
+}
+
+/// Client side of the cluster, submitting every request to each honest replica.
+struct Session {
+    client: Client<Response>,
+    requests: Vec<Sender<Request<Command>>>,
+    replies: Receiver<Reply<Response>>,
+    /// Replicas that answered the current request.
+    replied: BTreeSet<usize>,
+}
+
+impl Session {
+    /// Waits for enough replicas to agree on the response to `command`.
+    fn submit(&mut self, command: Command) -> Result<Response, String> {
+        let request = self.client.request(command);
+        self.replied.clear();
+        for sender in &self.requests {
+            sender
+                .send(request.clone())
+                .map_err(|_| "A replica stopped".to_string())?;
+        }
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let reply = self.receive(deadline)?;
+            if let Some(response) = self.client.handle_reply(reply) {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Waits for every honest replica to answer the current request.
+    fn wait_for_all(&mut self) -> Result<(), String> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while self.client.sequence() > 0 && self.replied.len() < self.requests.len() {
+            self.receive(deadline)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, deadline: Instant) -> Result<Reply<Response>, String> {
+        let reply = self
+            .replies
+            .recv_deadline(deadline)
+            .map_err(|_| "Timed out waiting for the replicas".to_string())?;
+        if reply.sequence == self.client.sequence() {
+            self.replied.insert(reply.replica_id);
+        }
+        Ok(reply)
+    }
+}
+
+fn main() {
+    let options = parse_options().unwrap_or_else(|error| {
+        eprintln!("{error}\n{USAGE}");
+        process::exit(2);
+    });
+    let honest_count = options.replicas - options.faulty;
+    let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(options.replicas));
+
+    let endpoints = network::connect(
+        options.replicas,
+        ChannelConfig::for_cluster(options.replicas),
+    );
+    let registry = Arc::new(Registry::new(options.replicas));
+    let server = options.metrics_port.map(|port| {
+        let server =
+            metrics::serve(registry.clone(), ("127.0.0.1", port)).unwrap_or_else(|error| {
+                eprintln!("Cannot serve metrics: {error}");
+                process::exit(2);
+            });
+        eprintln!("Serving metrics at http://{}/metrics", server.local_addr());
+        server
+    });
+    let client = Client::new(0, SigningKey::generate(&mut rand::thread_rng()), quorum);
+    let mut client_keys = ClientKeys::new();
+    client_keys.insert(client.id(), client.verifying_key());
+    let client_keys = Arc::new(client_keys);
+    let (reply_sender, replies) = channel::unbounded();
+    let mut requests = Vec::new();
+    let mut cluster = ClusterHandle::new();
+    for (id, (senders, inbox)) in endpoints.into_iter().enumerate() {
+        let network = NetworkInfo::new(id, senders, inbox).with_cancellation(cluster.token());
+        if id >= honest_count {
+            cluster.spawn(id, move || {
+                faulty::faulty_process(BroadcastValue::new(None, true), network);
+                None
+            });
+            continue;
+        }
+        let network = network.with_metrics(registry.clone());
+        let (request_sender, request_receiver) = channel::unbounded();
+        requests.push(request_sender);
+        let reply_sender = reply_sender.clone();
+        let window = options.window;
+        let client_keys = client_keys.clone();
+        cluster.spawn(id, move || {
+            let app = KvStore::default();
+            let app = byz_protocol::replica_protocol(
+                app,
+                client_keys,
+                window,
+                random_boolean,
+                request_receiver,
+                reply_sender,
+                network,
+            );
+            Some(app.snapshot())
+        });
+    }
+
+    let mut session = Session {
+        client,
+        requests,
+        replies,
+        replied: BTreeSet::new(),
+    };
+    for line in io::stdin().lock().lines() {
+        let line = line.expect("Expected to read standard input");
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_command(&line).and_then(|command| session.submit(command)) {
+            Ok(response) => println!("{response:?}"),
+            Err(error) => eprintln!("{error}"),
+        }
+    }
+
+    // Every honest replica applies the last request before their states are compared
+    if let Err(error) = session.wait_for_all() {
+        eprintln!("{error}");
+    }
+    if let Some(server) = server {
+        server.shutdown();
+    }
+    let report = cluster.shutdown(SHUTDOWN_DEADLINE);
+    let states: BTreeSet<_> = report.finished.values().flatten().collect();
+    if !report.is_clean() || states.len() > 1 {
+        eprintln!("Replicas diverged: {report:?}");
+        process::exit(1);
+    }
+    println!("{honest_count} honest replicas converged");
+}
+
+fn random_boolean() -> bool {
+    rand::thread_rng().gen_bool(0.5)
+}
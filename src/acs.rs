@@ -0,0 +1,339 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    broadcast::{BroadcastState, BroadcastValue},
+    messaging::{Message, MessageType},
+    node::{ConsensusNode, Step},
+    phase::LOOKAHEAD_ROUNDS,
+    quorum::QuorumSystem,
+    util::Broadcastable,
+};
+
+/// Message of a [`CommonSubset`], whose `slot` is the process the instance is about.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum AcsMessage<T> {
+    /// Part of the reliable broadcast of the slot's proposal.
+    Proposal(Message<T>),
+    /// Part of the binary agreement on whether to include the slot's proposal.
+    Agreement(Message<bool>),
+}
+
+impl<T> AcsMessage<T> {
+    pub fn slot(&self) -> usize {
+        match self {
+            AcsMessage::Proposal(message) => message.slot,
+            AcsMessage::Agreement(message) => message.slot,
+        }
+    }
+
+    pub fn sender_id(&self) -> usize {
+        match self {
+            AcsMessage::Proposal(message) => message.sender_id,
+            AcsMessage::Agreement(message) => message.sender_id,
+        }
+    }
+}
+
+/// What a [`CommonSubset`] asks of its runtime after an input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AcsStep<T> {
+    /// Messages to send to every process, the local one included.
+    pub messages: Vec<AcsMessage<T>>,
+    /// Slots whose agreement waits for a coin flip through [`CommonSubset::provide_coin`].
+    pub coin_requests: Vec<usize>,
+    /// Agreed proposals by proposer, in the one step that settled them.
+    pub output: Option<BTreeMap<usize, T>>,
+}
+
+impl<T> Default for AcsStep<T> {
+    fn default() -> Self {
+        AcsStep {
+            messages: Vec::new(),
+            coin_requests: Vec::new(),
+            output: None,
+        }
+    }
+}
+
+/// Asynchronous common subset, as used by HoneyBadgerBFT to agree on a batch of proposals.
+///
+/// Every process reliably broadcasts its proposal, and a binary agreement per process decides
+/// whether that proposal is included. A process votes for every proposal it receives until the
+/// included ones form a quorum, then votes against the remaining ones. Once every agreement has
+/// decided, the output is the included proposals, at least a quorum of them, the same at every
+/// correct process.
+///
+/// The instances are the [`BroadcastState`] and [`ConsensusNode`] that
+/// [`local_broadcast`](crate::broadcast::local_broadcast) and
+/// [`consensus_protocol`](crate::byz_protocol::consensus_protocol) drive for a single instance,
+/// told apart by the `slot` of their messages. Agreement messages received before the local vote
+/// are buffered, each sender getting as much room as a correct process could use ahead.
+#[derive(Clone, Debug)]
+pub struct CommonSubset<T> {
+    id: usize,
+    quorum: Arc<dyn QuorumSystem>,
+    broadcasts: Vec<BroadcastState<T>>,
+    agreements: BTreeMap<usize, ConsensusNode<bool>>,
+    early_messages: BTreeMap<usize, Vec<(usize, Message<bool>)>>,
+    early_senders: BTreeMap<(usize, usize), usize>,
+    included: BTreeMap<usize, bool>,
+    output: bool,
+}
+
+impl<T> CommonSubset<T>
+where
+    T: Broadcastable,
+{
+    pub fn new(id: usize, quorum: Arc<dyn QuorumSystem>) -> CommonSubset<T> {
+        let process_count = quorum.process_count();
+        CommonSubset {
+            id,
+            quorum,
+            broadcasts: (0..process_count)
+                .map(|_| BroadcastState::new(id))
+                .collect(),
+            agreements: BTreeMap::new(),
+            early_messages: BTreeMap::new(),
+            early_senders: BTreeMap::new(),
+            included: BTreeMap::new(),
+            output: false,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Whether each slot decided so far is included.
+    pub fn included(&self) -> &BTreeMap<usize, bool> {
+        &self.included
+    }
+
+    /// Whether the output is known and every agreement has terminated, so the local process can
+    /// stop.
+    pub fn terminated(&self) -> bool {
+        self.output && self.agreements.values().all(ConsensusNode::terminated)
+    }
+
+    /// Messages reliably broadcasting the local proposal.
+    pub fn propose(&self, value: T) -> AcsStep<T> {
+        let value = BroadcastValue::new(value, false);
+        let message = Message::new(0, self.id, self.id, value, MessageType::Initiate);
+        AcsStep {
+            messages: vec![AcsMessage::Proposal(message.with_slot(self.id))],
+            ..AcsStep::default()
+        }
+    }
+
+    /// Processes `message`, received from process `from` as authenticated by the runtime.
+    pub fn handle_message(&mut self, from: usize, message: AcsMessage<T>) -> AcsStep<T> {
+        let mut step = AcsStep::default();
+        let slot = message.slot();
+        let process_count = self.broadcasts.len();
+        if message.sender_id() != from || from >= process_count || slot >= process_count {
+            return step;
+        }
+        match message {
+            AcsMessage::Proposal(message) => self.handle_proposal(message, &mut step),
+            AcsMessage::Agreement(message) => match self.agreements.get_mut(&slot) {
+                Some(node) => {
+                    let node_step = node.handle_message(from, message);
+                    self.absorb(slot, node_step, &mut step);
+                }
+                None => self.buffer(from, message),
+            },
+        }
+        self.progress(&mut step);
+        step
+    }
+
+    /// Goes on with the outcome of the coin flip requested for `slot`.
+    pub fn provide_coin(&mut self, slot: usize, value: bool) -> AcsStep<T> {
+        let node = self
+            .agreements
+            .get_mut(&slot)
+            .unwrap_or_else(|| panic!("Expected the agreement of slot {slot} to be running"));
+        let node_step = node.provide_coin(value);
+        let mut step = AcsStep::default();
+        self.absorb(slot, node_step, &mut step);
+        self.progress(&mut step);
+        step
+    }
+
+    fn handle_proposal(&mut self, message: Message<T>, step: &mut AcsStep<T>) {
+        let slot = message.slot;
+        // Each slot only carries the broadcast of its own process
+        if message.broadcast_source_id != slot || message.message_type == MessageType::Decide {
+            return;
+        }
+        let broadcast = &mut self.broadcasts[slot];
+        let outgoing = broadcast.handle_message(self.quorum.as_ref(), message);
+        step.messages.extend(
+            outgoing
+                .into_iter()
+                .map(|message| AcsMessage::Proposal(message.with_slot(slot))),
+        );
+        if broadcast.delivered().is_some() && !self.agreements.contains_key(&slot) {
+            self.vote(slot, true, step);
+        }
+    }
+
+    /// Keeps an agreement message until the local process votes in its slot, unless its sender
+    /// already buffered more than a correct process could have sent.
+    fn buffer(&mut self, from: usize, message: Message<bool>) {
+        let sender_cap = LOOKAHEAD_ROUNDS * (2 * self.broadcasts.len() + 2);
+        let buffered = self.early_senders.entry((message.slot, from)).or_default();
+        if *buffered >= sender_cap {
+            return;
+        }
+        *buffered += 1;
+        self.early_messages
+            .entry(message.slot)
+            .or_default()
+            .push((from, message));
+    }
+
+    fn vote(&mut self, slot: usize, value: bool, step: &mut AcsStep<T>) {
+        let mut node = ConsensusNode::new(self.id, value, self.quorum.clone());
+        let mut node_steps = vec![node.start()];
+        for (from, message) in self.early_messages.remove(&slot).unwrap_or_default() {
+            node_steps.push(node.handle_message(from, message));
+        }
+        self.early_senders
+            .retain(|(buffered_slot, _), _| *buffered_slot != slot);
+        self.agreements.insert(slot, node);
+        for node_step in node_steps {
+            self.absorb(slot, node_step, step);
+        }
+    }
+
+    fn absorb(&mut self, slot: usize, node_step: Step<bool>, step: &mut AcsStep<T>) {
+        step.messages.extend(
+            node_step
+                .messages
+                .into_iter()
+                .map(|message| AcsMessage::Agreement(message.with_slot(slot))),
+        );
+        // Replayed messages may report the same pending flip more than once
+        if node_step.coin_requested && !step.coin_requests.contains(&slot) {
+            step.coin_requests.push(slot);
+        }
+        if let Some(value) = node_step.decided {
+            self.included.insert(slot, value);
+        }
+    }
+
+    /// Votes against the remaining slots once a quorum of them is included, and outputs the
+    /// included proposals once they are all decided and received.
+    fn progress(&mut self, step: &mut AcsStep<T>) {
+        let included = self
+            .included
+            .iter()
+            .filter(|(_, included)| **included)
+            .map(|(slot, _)| *slot)
+            .collect();
+        if self.quorum.is_quorum(&included) {
+            for slot in 0..self.broadcasts.len() {
+                if !self.agreements.contains_key(&slot) {
+                    self.vote(slot, false, step);
+                }
+            }
+        }
+
+        if self.output || self.included.len() < self.broadcasts.len() {
+            return;
+        }
+        let proposals: Option<BTreeMap<_, _>> = included
+            .iter()
+            .map(|slot| {
+                let value = self.broadcasts[*slot].delivered()?;
+                Some((*slot, value.value.clone()))
+            })
+            .collect();
+        if proposals.is_some() {
+            self.output = true;
+            step.output = proposals;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::quorum::ThresholdQuorum;
+
+    /// Runs a subset for each of the first `running` processes out of `process_count`, the
+    /// other ones staying silent, and returns what each of them output.
+    fn run(process_count: usize, running: usize) -> Vec<BTreeMap<usize, u32>> {
+        let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(process_count));
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut subsets: Vec<_> = (0..running)
+            .map(|id| CommonSubset::new(id, quorum.clone()))
+            .collect();
+        let mut in_flight: VecDeque<_> = subsets
+            .iter()
+            .flat_map(|subset| subset.propose(10 * subset.id() as u32).messages)
+            .collect();
+        let mut outputs = vec![None; running];
+
+        while let Some(message) = in_flight.pop_front() {
+            for subset in &mut subsets {
+                let mut step = subset.handle_message(message.sender_id(), message.clone());
+                while let Some(slot) = step.coin_requests.pop() {
+                    let next = subset.provide_coin(slot, rng.gen_bool(0.5));
+                    step.messages.extend(next.messages);
+                    step.coin_requests.extend(next.coin_requests);
+                    step.output = step.output.or(next.output);
+                }
+                if let Some(output) = step.output {
+                    assert!(outputs[subset.id()].is_none(), "Expected a single output");
+                    outputs[subset.id()] = Some(output);
+                }
+                in_flight.extend(step.messages);
+            }
+        }
+
+        assert!(subsets.iter().all(CommonSubset::terminated));
+        outputs.into_iter().map(Option::unwrap).collect()
+    }
+
+    #[test]
+    fn processes_output_the_same_subset() {
+        let outputs = run(4, 4);
+        assert!(
+            outputs.windows(2).all(|pair| pair[0] == pair[1]),
+            "{outputs:?}"
+        );
+        assert!(outputs[0].len() >= 3, "{outputs:?}");
+        assert!(outputs[0]
+            .iter()
+            .all(|(slot, value)| *value == 10 * *slot as u32));
+    }
+
+    #[test]
+    fn unknown_senders_take_no_room() {
+        let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(4));
+        let mut subset = CommonSubset::<u32>::new(0, quorum);
+        for from in 4..1000 {
+            let value = BroadcastValue::new(true, false);
+            let message = Message::new(0, from, from, value, MessageType::Initiate);
+            subset.handle_message(from, AcsMessage::Agreement(message.with_slot(1)));
+        }
+        assert!(subset.early_senders.is_empty());
+        assert!(subset.early_messages.is_empty());
+    }
+
+    #[test]
+    fn silent_processes_are_left_out() {
+        let outputs = run(7, 5);
+        let expected = (0..5).map(|slot| (slot, 10 * slot as u32)).collect();
+        assert!(
+            outputs.iter().all(|output| *output == expected),
+            "{outputs:?}"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::{error::Error, fmt, sync::Arc};
+
+use crate::{
+    messaging::Message, node::ConsensusNode, quorum::QuorumSystem, transport::Transport,
+    util::Broadcastable,
+};
+
+pub type Result<T, E = ConsensusError> = std::result::Result<T, E>;
+
+/// Why [`consensus`] stopped without a decision.
+#[derive(Debug)]
+pub enum ConsensusError {
+    Transport(Box<dyn Error + Send + Sync>),
+    /// The transport stopped delivering messages before the local process could terminate.
+    Closed,
+}
+
+impl fmt::Display for ConsensusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsensusError::Transport(error) => write!(f, "transport failed: {error}"),
+            ConsensusError::Closed => write!(f, "transport closed before termination"),
+        }
+    }
+}
+
+impl Error for ConsensusError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConsensusError::Transport(error) => Some(error.as_ref()),
+            ConsensusError::Closed => None,
+        }
+    }
+}
+
+fn transport_error(error: impl Error + Send + Sync + 'static) -> ConsensusError {
+    ConsensusError::Transport(Box::new(error))
+}
+
+/// Asynchronous counterpart of [`consensus_protocol`](crate::byz_protocol::consensus_protocol),
+/// driving the same state machine over `transport`. Cancelled by dropping the future.
+pub async fn consensus<T, R>(
+    id: usize,
+    initial_value: T,
+    quorum: Arc<dyn QuorumSystem>,
+    random_generator: impl FnMut() -> T,
+    transport: &mut R,
+) -> Result<T>
+where
+    T: Broadcastable,
+    R: Transport<T>,
+{
+    let node = ConsensusNode::new(id, initial_value, quorum);
+    run_node(node, random_generator, transport).await
+}
+
+/// Same as [`consensus`], driving a node set up by the caller, for instance
+/// [with metrics](ConsensusNode::with_metrics).
+pub async fn run_node<T, R>(
+    mut node: ConsensusNode<T>,
+    mut random_generator: impl FnMut() -> T,
+    transport: &mut R,
+) -> Result<T>
+where
+    T: Broadcastable,
+    R: Transport<T>,
+{
+    broadcast_all(transport, node.start().messages).await?;
+
+    // Deciding is not enough to stop: others may still need our messages to decide
+    while !node.terminated() {
+        let (from, message) = transport
+            .recv()
+            .await
+            .map_err(transport_error)?
+            .ok_or(ConsensusError::Closed)?;
+        let mut step = node.handle_message(from, message);
+        while step.coin_requested {
+            broadcast_all(transport, step.messages).await?;
+            step = node.provide_coin(random_generator());
+        }
+        broadcast_all(transport, step.messages).await?;
+    }
+    Ok(node
+        .decided()
+        .cloned()
+        .expect("Expected a terminated process to have decided"))
+}
+
+async fn broadcast_all<T, R>(transport: &mut R, messages: Vec<Message<T>>) -> Result<()>
+where
+    R: Transport<T>,
+{
+    for message in messages {
+        transport
+            .broadcast(message)
+            .await
+            .map_err(transport_error)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::{network::ChannelConfig, quorum::ThresholdQuorum, transport};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn tasks_agree() {
+        for process_count in 4..=7 {
+            let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(process_count));
+            let transports =
+                transport::local_cluster(process_count, ChannelConfig::for_cluster(process_count))
+                    .unwrap();
+            let tasks: Vec<_> = transports
+                .into_iter()
+                .map(|mut transport| {
+                    let quorum = quorum.clone();
+                    tokio::spawn(async move {
+                        let id = transport.id();
+                        let coin = || rand::thread_rng().gen_bool(0.5);
+                        consensus(id, id % 2 == 0, quorum, coin, &mut transport).await
+                    })
+                })
+                .collect();
+
+            let mut decisions = Vec::new();
+            for task in tasks {
+                decisions.push(task.await.unwrap().unwrap());
+            }
+            assert!(
+                decisions.windows(2).all(|pair| pair[0] == pair[1]),
+                "{decisions:?}"
+            );
+        }
+    }
+
+    /// Transport that delivers nothing.
+    struct ClosedTransport;
+
+    impl Transport<bool> for ClosedTransport {
+        type Error = std::convert::Infallible;
+
+        async fn broadcast(&mut self, _: Message<bool>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn recv(&mut self) -> Result<Option<(usize, Message<bool>)>, Self::Error> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_transport_closes() {
+        let quorum = Arc::new(ThresholdQuorum::new(4));
+        let result = consensus(0, true, quorum, || true, &mut ClosedTransport).await;
+        assert!(matches!(result, Err(ConsensusError::Closed)), "{result:?}");
+    }
+}
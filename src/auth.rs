@@ -0,0 +1,301 @@
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    messaging::Message,
+    metrics::{MetricEvent, MetricsSink},
+    transport::{Frame, Link, Transport},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of the keys handed out by [`PairwiseKeys::dealer`].
+const KEY_SIZE: usize = 32;
+
+/// Symmetric keys process `id` shares with every process, itself included, by process id.
+///
+/// A key known to a single pair of processes lets each of them tell the other's messages from
+/// forgeries by a third one. The keys are left out of the `Debug` output.
+#[derive(Clone)]
+pub struct PairwiseKeys {
+    id: usize,
+    keys: Vec<Vec<u8>>,
+}
+
+impl fmt::Debug for PairwiseKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PairwiseKeys")
+            .field("id", &self.id)
+            .field("process_count", &self.keys.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PairwiseKeys {
+    pub fn new(id: usize, keys: Vec<Vec<u8>>) -> PairwiseKeys {
+        assert!(id < keys.len(), "Expected a key shared with every process");
+        PairwiseKeys { id, keys }
+    }
+
+    /// Keys of every process of a cluster, by id, as a trusted dealer hands them out: a random
+    /// key per pair of processes.
+    pub fn dealer(process_count: usize, rng: &mut impl Rng) -> Vec<PairwiseKeys> {
+        let mut shared = BTreeMap::new();
+        (0..process_count)
+            .map(|id| {
+                let keys = (0..process_count)
+                    .map(|peer| {
+                        let pair = (id.min(peer), id.max(peer));
+                        let key = shared.entry(pair).or_insert_with(|| {
+                            (0..KEY_SIZE).map(|_| rng.gen()).collect::<Vec<u8>>()
+                        });
+                        key.clone()
+                    })
+                    .collect();
+                PairwiseKeys::new(id, keys)
+            })
+            .collect()
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn process_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// MAC under the key shared with `peer`, unless there is no such process.
+    fn mac(&self, peer: usize) -> Option<HmacSha256> {
+        let key = self.keys.get(peer)?;
+        Some(HmacSha256::new_from_slice(key).expect("Expected HMAC to take keys of any size"))
+    }
+
+    /// Tag authenticating `payload` to `peer`, unless there is no such process.
+    pub fn sign(&self, peer: usize, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut mac = self.mac(peer)?;
+        mac.update(payload);
+        Some(mac.finalize().into_bytes().to_vec())
+    }
+
+    /// Whether `tag` authenticates `payload` as sent by `peer`, compared in constant time. Tags
+    /// from unknown processes never do.
+    pub fn verify(&self, peer: usize, payload: &[u8], tag: &[u8]) -> bool {
+        let Some(mut mac) = self.mac(peer) else {
+            return false;
+        };
+        mac.update(payload);
+        mac.verify_slice(tag).is_ok()
+    }
+}
+
+/// [`Transport`] over a [`Link`] that only lets through messages from the process they claim
+/// to come from.
+///
+/// Each message is encoded once and sent to every process with an HMAC-SHA256 tag under the key
+/// of the pair. Received frames are dropped when the claimed sender is unknown, when the tag
+/// does not match its key, or when the message inside names another sender. Rejections are
+/// counted, logged as warnings and reported to the metrics sink, if any.
+#[derive(Debug)]
+pub struct AuthenticatedTransport<L> {
+    keys: PairwiseKeys,
+    link: L,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    rejected: usize,
+}
+
+impl<L> AuthenticatedTransport<L>
+where
+    L: Link,
+{
+    pub fn new(keys: PairwiseKeys, link: L) -> AuthenticatedTransport<L> {
+        assert_eq!(
+            keys.process_count(),
+            link.process_count(),
+            "Expected a key shared with every process of the link"
+        );
+        AuthenticatedTransport {
+            keys,
+            link,
+            metrics: None,
+            rejected: 0,
+        }
+    }
+
+    /// Reports rejected frames to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> AuthenticatedTransport<L> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn id(&self) -> usize {
+        self.keys.id()
+    }
+
+    /// Frames dropped so far.
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    fn authenticate<T>(&self, frame: &Frame) -> Result<Message<T>, String>
+    where
+        T: DeserializeOwned,
+    {
+        if frame.from >= self.keys.process_count() {
+            return Err(format!("Unknown sender {}", frame.from));
+        }
+        if !self.keys.verify(frame.from, &frame.payload, &frame.tag) {
+            return Err("Invalid MAC".to_string());
+        }
+        let message = Message::decode(&frame.payload)?;
+        if message.sender_id != frame.from {
+            return Err(format!("Message from {} relayed", message.sender_id));
+        }
+        Ok(message)
+    }
+}
+
+impl<T, L> Transport<T> for AuthenticatedTransport<L>
+where
+    T: Serialize + DeserializeOwned + Send,
+    L: Link + Send,
+{
+    type Error = L::Error;
+
+    async fn broadcast(&mut self, message: Message<T>) -> Result<(), L::Error> {
+        let payload = message.encode();
+        for to in 0..self.keys.process_count() {
+            let frame = Frame {
+                from: self.id(),
+                tag: self
+                    .keys
+                    .sign(to, &payload)
+                    .expect("Expected a key shared with every process of the link"),
+                payload: payload.clone(),
+            };
+            self.link.send(to, frame).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<(usize, Message<T>)>, L::Error> {
+        while let Some(frame) = self.link.recv().await? {
+            match self.authenticate(&frame) {
+                Ok(message) => return Ok(Some((frame.from, message))),
+                Err(reason) => {
+                    tracing::warn!(node = self.id(), from = frame.from, %reason, "frame rejected");
+                    self.rejected += 1;
+                    if let Some(metrics) = &self.metrics {
+                        metrics.record(self.id(), MetricEvent::Unauthenticated);
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        async_protocol,
+        broadcast::BroadcastValue,
+        messaging::MessageType,
+        metrics::Registry,
+        network::ChannelConfig,
+        quorum::{QuorumSystem, ThresholdQuorum},
+        transport,
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn tasks_agree_over_authenticated_links() {
+        let process_count = 4;
+        let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(process_count));
+        let keys = PairwiseKeys::dealer(process_count, &mut StdRng::seed_from_u64(0));
+        let links =
+            transport::local_links(process_count, ChannelConfig::for_cluster(process_count))
+                .unwrap();
+        let tasks: Vec<_> = keys
+            .into_iter()
+            .zip(links)
+            .map(|(keys, link)| {
+                let quorum = quorum.clone();
+                tokio::spawn(async move {
+                    let mut transport = AuthenticatedTransport::new(keys, link);
+                    let id = transport.id();
+                    let coin = || rand::thread_rng().gen_bool(0.5);
+                    let decided =
+                        async_protocol::consensus(id, id < 2, quorum, coin, &mut transport).await;
+                    (decided.unwrap(), transport.rejected())
+                })
+            })
+            .collect();
+
+        let mut decisions = Vec::new();
+        for task in tasks {
+            let (decided, rejected) = task.await.unwrap();
+            assert_eq!(rejected, 0);
+            decisions.push(decided);
+        }
+        assert!(
+            decisions.windows(2).all(|pair| pair[0] == pair[1]),
+            "{decisions:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn forged_frames_are_rejected_and_counted() {
+        let keys = PairwiseKeys::dealer(4, &mut StdRng::seed_from_u64(0));
+        let mut links = transport::local_links(4, ChannelConfig::unbounded()).unwrap();
+        let registry = Arc::new(Registry::new(4));
+        let mut forger = links.pop().unwrap();
+        let mut transport = AuthenticatedTransport::new(keys[0].clone(), links.remove(0))
+            .with_metrics(registry.clone());
+
+        let message = |sender| {
+            let value = BroadcastValue::new(true, false);
+            Message::new(0, sender, sender, value, MessageType::Initiate)
+        };
+        let frame = |from, keys: &PairwiseKeys, message: Message<bool>| {
+            let payload = message.encode();
+            Frame {
+                from,
+                tag: keys.sign(0, &payload).unwrap(),
+                payload,
+            }
+        };
+        // Process 3 posing as process 1 with its own key, relaying a message of process 1 under
+        // its own name, and claiming an unknown sender, before sending a genuine message
+        for forged in [
+            frame(1, &keys[3], message(1)),
+            frame(3, &keys[3], message(1)),
+            frame(7, &keys[3], message(3)),
+            frame(3, &keys[3], message(3)),
+        ] {
+            forger.send(0, forged).await.unwrap();
+        }
+
+        let received: Option<(usize, Message<bool>)> = transport.recv().await.unwrap();
+        assert_eq!(received, Some((3, message(3))));
+        assert_eq!(transport.rejected(), 3);
+        assert!(registry
+            .render()
+            .contains("consensus_unauthenticated_frames_total{node=\"0\"} 3\n"));
+    }
+
+    #[test]
+    fn unknown_peers_have_no_key() {
+        let keys = PairwiseKeys::dealer(4, &mut StdRng::seed_from_u64(0));
+        let tag = keys[0].sign(1, b"payload").unwrap();
+        assert!(keys[1].verify(0, b"payload", &tag));
+        assert_eq!(keys[0].sign(4, b"payload"), None);
+        assert!(!keys[1].verify(4, b"payload", &tag));
+    }
+}
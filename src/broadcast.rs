@@ -1,24 +1,33 @@
-use std::{cmp, collections::HashMap, hash};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash,
+    sync::Arc,
+};
 
-use crossbeam::channel::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     messaging::{Message, MessageType},
-    util::Broadcastable,
+    network::{Inbox, PeerSender},
+    quorum::QuorumSystem,
+    util::{Broadcastable, CancellationToken},
 };
 
+/// Distinct senders seen for each value.
+type SenderSets<T> = BTreeMap<BroadcastValue<T>, BTreeSet<usize>>;
+
 #[derive(Clone)]
 pub struct BroadcastSender<T> {
-    senders: Vec<Sender<Message<T>>>,
+    id: usize,
+    senders: Vec<PeerSender<T>>,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, hash::Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, hash::Hash, Serialize, Deserialize)]
 pub struct BroadcastValue<T> {
     pub value: T,
     pub decided: bool,
 }
 
-
 impl<T> BroadcastValue<T> {
     pub fn new(value: T, decided: bool) -> BroadcastValue<T> {
         BroadcastValue { value, decided }
@@ -29,218 +38,488 @@ impl<T> BroadcastSender<T>
 where
     T: Broadcastable,
 {
+    pub fn new(id: usize, senders: Vec<PeerSender<T>>) -> BroadcastSender<T> {
+        BroadcastSender { id, senders }
+    }
 
-    pub fn new(senders: Vec<Sender<Message<T>>>) -> BroadcastSender<T> {
-        BroadcastSender { senders }
+    pub fn id(&self) -> usize {
+        self.id
     }
 
     pub fn send(&self, msg: Message<T>) {
         for sender in &self.senders {
+            // Peers that have already finished drop their inbox, full inboxes count the drop
             sender.send(msg.clone());
         }
     }
 }
 
-
 pub fn local_broadcast<T>(
     round: usize,
-    broadcast_source_id: usize,
     initial_value: BroadcastValue<T>,
-    process_count: usize,
-    receiver: Receiver<Message<T>>,
+    quorum: Arc<dyn QuorumSystem>,
+    inbox: Inbox<T>,
     sender: BroadcastSender<T>,
-) -> BroadcastValue<T>
+    cancel: &CancellationToken,
+) -> Option<BroadcastValue<T>>
 where
     T: Broadcastable,
 {
     //Send initial message
     sender.send(Message::new(
         round,
-        broadcast_source_id,
+        sender.id(),
+        sender.id(),
         initial_value,
         MessageType::Initiate,
     ));
-    broadcast_protocol(process_count, receiver, sender)
+    broadcast_protocol(quorum, inbox, sender, cancel)
 }
 
 pub fn broadcast_protocol<T>(
-    process_count: usize,
-    receiver: Receiver<Message<T>>,
+    quorum: Arc<dyn QuorumSystem>,
+    mut inbox: Inbox<T>,
     sender: BroadcastSender<T>,
-) -> BroadcastValue<T>
+    cancel: &CancellationToken,
+) -> Option<BroadcastValue<T>>
 where
     T: Broadcastable,
 {
-    let faulty_count = if process_count % 3 == 0 {
-        process_count / 3 - 1
-    } else {
-        process_count / 3
-    };
-    let mut echo_count = HashMap::new();
-    let mut ready_count = HashMap::new();
-
-    let (echo_count, ready_count) = broadcast_stage_one(
-        process_count,
-        faulty_count,
-        echo_count,
-        ready_count,
-        &receiver,
-        &sender,
-    );
-    let ready_count = broadcast_stage_two(
-        process_count,
-        faulty_count,
-        echo_count,
-        ready_count,
-        &receiver,
-        &sender,
-    );
-
-    broadcast_stage_three(faulty_count, ready_count, &receiver)
+    let mut state = BroadcastState::new(sender.id());
+    while let Some((from, message)) = inbox.recv(cancel) {
+        // Messages naming another sender than the peer they came from are forged
+        if message.sender_id != from {
+            continue;
+        }
+        for outgoing in state.handle_message(quorum.as_ref(), message) {
+            sender.send(outgoing);
+        }
+        if let Some(value) = state.delivered() {
+            return Some(value.clone());
+        }
+    }
+    None
+}
+
+/// Event-driven state of one Bracha reliable broadcast instance at one process.
+///
+/// Each step consumes a received message and returns the messages to send to every process:
+/// - the first `Initiate` from the source is echoed,
+/// - echoes from a quorum, or readies from a blocking set, make the process send `Ready`,
+/// - readies from a quorum deliver the value.
+///
+/// With `n = 3f + 1` processes of equal weight these are the usual `> (n + f) / 2`
+/// echoes, `f + 1` ready amplification and `2f + 1` delivery thresholds.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct BroadcastState<T> {
+    id: usize,
+    echoed: bool,
+    readied: bool,
+    delivered: Option<BroadcastValue<T>>,
+    echo_senders: SenderSets<T>,
+    ready_senders: SenderSets<T>,
 }
 
-fn broadcast_stage_one<T>(
-    process_count: usize,
-    faulty_count: usize,
-    mut echo_count: HashMap<T, usize>,
-    mut ready_count: HashMap<T, usize>,
-    receiver: &Receiver<Message<T>>,
-    sender: &BroadcastSender<T>,
-) -> (HashMap<T, usize>, HashMap<T, usize>)
+impl<T> BroadcastState<T>
 where
     T: Broadcastable,
 {
-    let mut initialized = false;
+    pub fn new(id: usize) -> BroadcastState<T> {
+        BroadcastState {
+            id,
+            echoed: false,
+            readied: false,
+            delivered: None,
+            echo_senders: BTreeMap::new(),
+            ready_senders: BTreeMap::new(),
+        }
+    }
+
+    pub fn delivered(&self) -> Option<&BroadcastValue<T>> {
+        self.delivered.as_ref()
+    }
 
-    while !initialized {
+    pub fn handle_message(
+        &mut self,
+        quorum: &dyn QuorumSystem,
+        message: Message<T>,
+    ) -> Vec<Message<T>> {
         let Message {
             round,
+            sender_id,
             broadcast_source_id,
             message_type,
             value,
-        } = receiver.recv().unwrap();
+            ..
+        } = message;
+        let reply = |value, message_type| {
+            Message::new(round, self.id, broadcast_source_id, value, message_type)
+        };
+
+        let mut outgoing = Vec::new();
+        if sender_id >= quorum.process_count() {
+            return outgoing;
+        }
         match message_type {
+            // Once delivered, the local ready has been sent and the senders are no longer needed
+            MessageType::Echo | MessageType::Ready if self.delivered.is_some() => (),
             MessageType::Initiate => {
-                initialized = true;
-                sender.send(Message::new(
-                    round,
-                    broadcast_source_id,
-                    value,
-                    MessageType::Echo,
-                ));
+                // Only the source of the broadcast may initiate it
+                if sender_id == broadcast_source_id && !self.echoed {
+                    outgoing.push(reply(value, MessageType::Echo));
+                }
             }
             MessageType::Echo => {
-                let entry = echo_count.entry(value.value.clone()).or_insert(0);
-                *entry += 1;
-                if *entry >= (process_count + faulty_count) / 2 {
-                    initialized = true;
-                    sender.send(Message::new(
-                        round,
-                        broadcast_source_id,
-                        value,
-                        MessageType::Echo,
-                    ));
+                let Some(senders) = add_sender(&mut self.echo_senders, &value, sender_id) else {
+                    return outgoing;
+                };
+                if quorum.is_quorum(senders) && !self.readied {
+                    tracing::debug!(value = ?value.value, decided = value.decided, "echo quorum reached");
+                    outgoing.push(reply(value, MessageType::Ready));
                 }
             }
             MessageType::Ready => {
-                let entry = ready_count.entry(value.value.clone()).or_insert(0);
-                *entry += 1;
-                if *entry >= (process_count + faulty_count) / 2 {
-                    initialized = true;
-                    sender.send(Message::new(
-                        round,
-                        broadcast_source_id,
-                        value,
-                        MessageType::Echo,
-                    ));
+                let Some(senders) = add_sender(&mut self.ready_senders, &value, sender_id) else {
+                    return outgoing;
+                };
+                if quorum.is_quorum(senders) && self.delivered.is_none() {
+                    tracing::debug!(value = ?value.value, decided = value.decided, "value delivered");
+                    self.delivered = Some(value.clone());
+                }
+                if quorum.is_blocking(senders) && !self.readied {
+                    outgoing.push(reply(value, MessageType::Ready));
+                }
+            }
+            MessageType::Decide => (),
+        }
+
+        for message in &outgoing {
+            let value = &message.value;
+            match message.message_type {
+                MessageType::Echo => self.echoed = true,
+                MessageType::Ready => {
+                    tracing::debug!(value = ?value.value, decided = value.decided, "ready sent");
+                    self.readied = true;
                 }
+                MessageType::Initiate | MessageType::Decide => (),
             }
         }
+        if self.delivered.is_some() {
+            self.echo_senders.clear();
+            self.ready_senders.clear();
+        }
+        outgoing
     }
-    (echo_count, ready_count)
 }
 
-fn broadcast_stage_two<T>(
-    process_count: usize,
-    faulty_count: usize,
-    mut echo_count: HashMap<T, usize>,
-    mut ready_count: HashMap<T, usize>,
-    receiver: &Receiver<Message<T>>,
-    sender: &BroadcastSender<T>,
-) -> HashMap<T, usize>
+/// Records `sender_id` as a sender of `value` and returns all distinct senders of `value` so far.
+///
+/// A correct process sends a single echo and a single ready per instance, so a sender is only
+/// counted for the first value it sends: later ones are ignored, returning `None`. This keeps
+/// faulty processes from adding a set for every value they make up.
+fn add_sender<'a, T>(
+    senders: &'a mut SenderSets<T>,
+    value: &BroadcastValue<T>,
+    sender_id: usize,
+) -> Option<&'a BTreeSet<usize>>
 where
     T: Broadcastable,
 {
-    let mut readied = false;
+    let counted = senders
+        .iter()
+        .any(|(counted, set)| counted != value && set.contains(&sender_id));
+    if counted {
+        return None;
+    }
+    let entry = senders.entry(value.clone()).or_default();
+    entry.insert(sender_id);
+    Some(entry)
+}
 
-    while !readied {
-        let Message {
-            round,
-            broadcast_source_id,
-            message_type,
-            value,
-        } = receiver.recv().unwrap();
-        match message_type {
-            MessageType::Initiate => (), //Discard message
-            MessageType::Echo => {
-                let entry = echo_count.entry(value.value.clone()).or_insert(0);
-                *entry += 1;
-                if *entry >= (process_count + faulty_count) / 2 {
-                    readied = true;
-                    sender.send(Message::new(
-                        round,
-                        broadcast_source_id,
-                        value,
-                        MessageType::Ready,
-                    ));
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{quorum::ThresholdQuorum, util};
+
+    const VALUES: [bool; 2] = [false, true];
+
+    /// Per value: distinct honest and Byzantine senders seen.
+    type Counts = Vec<(usize, usize)>;
+
+    type NodeKey = (bool, bool, Option<bool>, Counts, Counts);
+
+    type Key = (Vec<NodeKey>, Vec<(MessageType, bool)>);
+
+    /// Messages of one type and value delivered to one process in a single step.
+    type Step = (usize, MessageType, bool, Vec<usize>);
+
+    /// One global state of a broadcast instance where processes `0..honest_count` follow the
+    /// protocol and the remaining ones may send any message to anyone at any time.
+    #[derive(Clone)]
+    struct World {
+        process_count: usize,
+        honest_count: usize,
+        source: usize,
+        nodes: Vec<BroadcastState<bool>>,
+        /// Honest messages, each sent to every honest process
+        sent: BTreeSet<Message<bool>>,
+        /// Messages already received by each honest process
+        received: Vec<BTreeSet<Message<bool>>>,
+    }
+
+    impl World {
+        fn new(process_count: usize, source: usize, value: bool) -> World {
+            let honest_count = process_count - util::faulty_count(process_count);
+            let mut sent = BTreeSet::new();
+            if source < honest_count {
+                sent.insert(message(source, source, MessageType::Initiate, value));
+            }
+            World {
+                process_count,
+                honest_count,
+                source,
+                nodes: (0..honest_count).map(BroadcastState::new).collect(),
+                sent,
+                received: vec![BTreeSet::new(); honest_count],
+            }
+        }
+
+        /// Messages of the given type and value that `node` has not received yet, honest ones
+        /// first. Byzantine processes are assumed to send every such message.
+        fn pending(
+            &self,
+            node: usize,
+            message_type: MessageType,
+            value: bool,
+            honest_only: bool,
+        ) -> (Vec<Message<bool>>, Vec<Message<bool>>) {
+            let honest = self
+                .sent
+                .iter()
+                .filter(|m| m.message_type == message_type && m.value.value == value)
+                .filter(|m| !self.received[node].contains(m))
+                .cloned()
+                .collect();
+            let byzantine = (self.honest_count..self.process_count)
+                .filter(|_| !honest_only)
+                .filter(|sender| message_type != MessageType::Initiate || *sender == self.source)
+                .map(|sender| message(sender, self.source, message_type, value))
+                .filter(|m| !self.received[node].contains(m))
+                .collect();
+            (honest, byzantine)
+        }
+
+        /// Smallest deliveries of a single message type and value that change some process'
+        /// state. Every interleaving is a sequence of such steps: messages that do not make a
+        /// threshold cross can be delayed without changing anyone's behaviour.
+        fn steps(&self, honest_only: bool) -> Vec<Step> {
+            let mut steps = Vec::new();
+            for node in 0..self.honest_count {
+                for message_type in [MessageType::Initiate, MessageType::Echo, MessageType::Ready] {
+                    for value in VALUES {
+                        let (honest, byzantine) =
+                            self.pending(node, message_type, value, honest_only);
+                        for count in 1..=honest.len() + byzantine.len() {
+                            let found: Vec<_> = (0..=count.min(honest.len()))
+                                .filter(|taken| count - taken <= byzantine.len())
+                                .map(|taken| {
+                                    let senders = honest[..taken]
+                                        .iter()
+                                        .chain(&byzantine[..count - taken])
+                                        .map(|m| m.sender_id)
+                                        .collect();
+                                    (node, message_type, value, senders)
+                                })
+                                .filter(|step| self.changes_state(step))
+                                .collect();
+                            if !found.is_empty() {
+                                steps.extend(found);
+                                break;
+                            }
+                        }
+                    }
                 }
             }
-            MessageType::Ready => {
-                let entry = ready_count.entry(value.value.clone()).or_insert(0);
-                *entry += 1;
-                if *entry >= (process_count + faulty_count) / 2 {
-                    readied = true;
-                    sender.send(Message::new(
-                        round,
-                        broadcast_source_id,
-                        value,
-                        MessageType::Ready,
-                    ));
+            steps
+        }
+
+        fn changes_state(&self, (node, message_type, value, senders): &Step) -> bool {
+            let quorum = ThresholdQuorum::new(self.process_count);
+            let before = &self.nodes[*node];
+            let mut after = before.clone();
+            for sender in senders {
+                after.handle_message(
+                    &quorum,
+                    message(*sender, self.source, *message_type, *value),
+                );
+            }
+            (before.echoed, before.readied, &before.delivered)
+                != (after.echoed, after.readied, &after.delivered)
+        }
+
+        fn apply(&mut self, (node, message_type, value, senders): &Step) {
+            let quorum = ThresholdQuorum::new(self.process_count);
+            for sender in senders {
+                let message = message(*sender, self.source, *message_type, *value);
+                self.received[*node].insert(message.clone());
+                let outgoing = self.nodes[*node].handle_message(&quorum, message);
+                self.sent.extend(outgoing);
+            }
+        }
+
+        fn counts(&self, senders: &SenderSets<bool>) -> Counts {
+            VALUES
+                .iter()
+                .map(|value| {
+                    let senders = senders.get(&BroadcastValue::new(*value, false));
+                    let honest = senders.map_or(0, |s| s.range(..self.honest_count).count());
+                    let byzantine = senders.map_or(0, |s| s.range(self.honest_count..).count());
+                    (honest, byzantine)
+                })
+                .collect()
+        }
+
+        /// State up to renaming processes within the honest and the Byzantine group, ignoring
+        /// counts that can no longer influence a process.
+        fn key(&self) -> Key {
+            let mut nodes: Vec<_> = self
+                .nodes
+                .iter()
+                .map(|node| {
+                    let echoes = if node.readied {
+                        Vec::new()
+                    } else {
+                        self.counts(&node.echo_senders)
+                    };
+                    let readies = if node.delivered.is_some() {
+                        Vec::new()
+                    } else {
+                        self.counts(&node.ready_senders)
+                    };
+                    let delivered = node.delivered.as_ref().map(|value| value.value);
+                    (node.echoed, node.readied, delivered, echoes, readies)
+                })
+                .collect();
+            nodes.sort();
+            let mut sent: Vec<_> = self
+                .sent
+                .iter()
+                .map(|message| (message.message_type, message.value.value))
+                .collect();
+            sent.sort();
+            (nodes, sent)
+        }
+
+        /// Consistency and validity in every state. Once honest messages alone cannot change
+        /// anything more, totality and delivery of an honest source's value must also hold.
+        fn check(&self, value: bool) -> Result<(), String> {
+            let delivered: Vec<_> = self
+                .nodes
+                .iter()
+                .map(|node| node.delivered().map(|value| value.value))
+                .collect();
+            let values: BTreeSet<_> = delivered.iter().flatten().collect();
+            let honest_source = self.source < self.honest_count;
+            if values.len() > 1 {
+                return Err(format!("consistency violated: {delivered:?}"));
+            }
+            if honest_source && values.iter().any(|v| **v != value) {
+                return Err(format!("validity violated: {delivered:?}"));
+            }
+            if self.steps(true).is_empty() {
+                let all_delivered = delivered.iter().all(Option::is_some);
+                if honest_source && !all_delivered {
+                    return Err(format!("honest source not delivered: {delivered:?}"));
+                }
+                if !values.is_empty() && !all_delivered {
+                    return Err(format!("totality violated: {delivered:?}"));
                 }
             }
+            Ok(())
         }
     }
 
-    ready_count
-}
-
-fn broadcast_stage_three<T>(
-    faulty_count: usize,
-    mut ready_count: HashMap<T, usize>,
-    receiver: &Receiver<Message<T>>,
-) -> BroadcastValue<T>
-where
-    T: Broadcastable,
-{
-    let mut result = None;
-    while result.is_none() {
-        let Message {
-            round,
-            broadcast_source_id,
+    fn message(
+        sender: usize,
+        source: usize,
+        message_type: MessageType,
+        value: bool,
+    ) -> Message<bool> {
+        Message::new(
+            0,
+            sender,
+            source,
+            BroadcastValue::new(value, false),
             message_type,
-            value,
-        } = receiver.recv().unwrap();
+        )
+    }
 
-        match message_type {
-            MessageType::Initiate | MessageType::Echo => (), //Discard message
-            MessageType::Ready => {
-                let entry = ready_count.entry(value.value.clone()).or_insert(0);
-                *entry += 1;
-                if *entry >= 2 * faulty_count + 1 {
-                    result = Some(value);
+    /// Explores every interleaving of honest and Byzantine messages, returning the number of
+    /// distinct states or the trace leading to a violation.
+    fn explore(process_count: usize, source: usize, value: bool) -> Result<usize, String> {
+        let mut visited = HashSet::new();
+        let mut trace = Vec::new();
+        let world = World::new(process_count, source, value);
+        explore_from(world, value, &mut visited, &mut trace)?;
+        Ok(visited.len())
+    }
+
+    fn explore_from(
+        world: World,
+        value: bool,
+        visited: &mut HashSet<Key>,
+        trace: &mut Vec<Step>,
+    ) -> Result<(), String> {
+        if !visited.insert(world.key()) {
+            return Ok(());
+        }
+        if let Err(violation) = world.check(value) {
+            return Err(format!("{violation} after {trace:?}"));
+        }
+        for step in world.steps(false) {
+            let mut next = world.clone();
+            next.apply(&step);
+            trace.push(step);
+            explore_from(next, value, visited, trace)?;
+            trace.pop();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn senders_are_counted_for_their_first_value_only() {
+        let quorum = ThresholdQuorum::new(4);
+        let mut state = BroadcastState::new(0);
+        for value in 0..1000 {
+            for message_type in [MessageType::Echo, MessageType::Ready] {
+                let value = BroadcastValue::new(value, false);
+                state.handle_message(&quorum, Message::new(0, 3, 3, value, message_type));
+            }
+        }
+        state.handle_message(
+            &quorum,
+            Message::new(0, 9, 3, BroadcastValue::new(0, false), MessageType::Echo),
+        );
+
+        assert_eq!(state.echo_senders.len(), 1);
+        assert_eq!(state.ready_senders.len(), 1);
+        assert_eq!(state.echo_senders.values().next().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn broadcast_is_consistent_and_total_for_small_configurations() {
+        for process_count in 4..=7 {
+            // Processes within the honest and the Byzantine group are interchangeable, so one
+            // honest and one Byzantine source cover every choice of sender
+            for source in [0, process_count - 1] {
+                for value in VALUES {
+                    if let Err(violation) = explore(process_count, source, value) {
+                        panic!("n = {process_count}, source = {source}: {violation}");
+                    }
                 }
             }
         }
     }
-    result.unwrap()
 }
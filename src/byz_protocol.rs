@@ -1,51 +1,134 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::{util::{Broadcastable, NetworkInfo}, broadcast::{BroadcastSender, BroadcastValue}, phase};
+use crossbeam::channel::{Receiver, Sender};
 
+use crate::{
+    broadcast::BroadcastSender,
+    network::Received,
+    node::ConsensusNode,
+    smr::{Application, ClientKeys, Replica, Reply, Request},
+    util::{Broadcastable, NetworkInfo},
+};
 
+pub fn consensus_protocol<T>(
+    initial_value: T,
+    random_generator: fn() -> T,
+    network: NetworkInfo<T>,
+) -> Option<T>
+where
+    T: Broadcastable,
+{
+    consensus_with_coin(initial_value, |_| random_generator(), network)
+        .map(|decision| decision.value)
+}
 
+/// Value decided by a process, with the phase it decided in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision<T> {
+    pub value: T,
+    pub phase: usize,
+}
 
+/// Same as [`consensus_protocol`], with a coin told the phase it is flipped for so that it can
+/// be common to every process.
+pub fn consensus_with_coin<T>(
+    initial_value: T,
+    mut coin: impl FnMut(usize) -> T,
+    network: NetworkInfo<T>,
+) -> Option<Decision<T>>
+where
+    T: Broadcastable,
+{
+    let NetworkInfo {
+        id,
+        senders,
+        mut inbox,
+        quorum,
+        cancel,
+        metrics,
+    } = network;
 
+    let sender = BroadcastSender::new(id, senders);
+    let mut node = ConsensusNode::new(id, initial_value, quorum);
+    if let Some(metrics) = metrics {
+        node = node.with_metrics(metrics);
+    }
+    for message in node.start().messages {
+        sender.send(message);
+    }
 
+    // Deciding is not enough to stop: others may still need our messages to decide
+    let mut decision = None;
+    while !node.terminated() {
+        let (from, message) = inbox.recv(&cancel)?;
+        let mut step = node.handle_message(from, message);
+        loop {
+            if let Some(value) = step.decided {
+                let phase = node.state().phase();
+                decision = Some(Decision { value, phase });
+            }
+            for message in step.messages {
+                sender.send(message);
+            }
+            if !step.coin_requested {
+                break;
+            }
+            step = node.provide_coin(coin(node.state().phase()));
+        }
+    }
+    assert!(
+        decision.is_some(),
+        "Expected a terminated process to have decided"
+    );
+    decision
+}
 
-pub fn consensus_protocol<T>(initial_value: T, random_generator: fn() -> T, network: NetworkInfo<T>) -> T
+/// Runs a replica of `app` until cancelled or cut off from every peer, ordering the requests
+/// received on `requests` with the other replicas over a log of `window` concurrent slots. Every
+/// request applied is answered on `replies`, whichever replica it was submitted to, and requests
+/// not signed by one of the `client_keys` are dropped. Returns the application as the replica
+/// left it.
+pub fn replica_protocol<A>(
+    app: A,
+    client_keys: Arc<ClientKeys>,
+    window: usize,
+    random_generator: fn() -> bool,
+    requests: Receiver<Request<A::Command>>,
+    replies: Sender<Reply<A::Response>>,
+    network: NetworkInfo<Option<Request<A::Command>>>,
+) -> A
 where
-    T: Broadcastable,
+    A: Application,
 {
     let NetworkInfo {
         id,
         senders,
-        mut receiver,
+        mut inbox,
+        quorum,
+        cancel,
+        metrics,
     } = network;
-    let process_count = senders.len();
-    let faulty_count = if process_count % 3 == 0 {
-        process_count / 3 - 1
-    } else {
-        process_count / 3
-    };
-
-    let mut phase_counter = 0;
-    let mut early_messages = HashMap::new();
-    let sender = BroadcastSender::new(senders);
-    let mut current_value = BroadcastValue::new(initial_value, false);
-
-    let mut decided = false;
-    while !decided {
-        (current_value, early_messages, receiver) = phase::phase(
-            id,
-            process_count,
-            faulty_count,
-            phase_counter,
-            current_value,
-            sender.clone(),
-            receiver,
-            early_messages,
-            random_generator,
-        );
-        if current_value.decided {
-            decided = true;
+
+    let sender = BroadcastSender::new(id, senders);
+    let mut replica = Replica::new(id, quorum, client_keys, window, app);
+    if let Some(metrics) = metrics {
+        replica = replica.with_metrics(metrics);
+    }
+    while let Some(received) = inbox.recv_or(&cancel, &requests) {
+        let mut step = match received {
+            Received::Message(from, message) => replica.handle_message(from, message),
+            Received::Other(request) => replica.submit(request),
+        };
+        while let Some(slot) = step.coin_requests.pop() {
+            step.extend(replica.provide_coin(slot, random_generator()));
+        }
+        for message in step.messages {
+            sender.send(message);
+        }
+        for reply in step.replies {
+            // Clients that went away miss their replies
+            let _ = replies.send(reply);
         }
-        phase_counter += 1;
     }
-    current_value.value
+    replica.into_app()
 }
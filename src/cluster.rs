@@ -0,0 +1,158 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    panic,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::util::CancellationToken;
+
+/// How often [`ClusterHandle::shutdown`] checks whether the remaining threads have stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Threads running the processes of a local cluster, each returning what its process decided.
+pub struct ClusterHandle<T> {
+    cancel: CancellationToken,
+    workers: BTreeMap<usize, JoinHandle<Option<T>>>,
+}
+
+/// Outcome of [`ClusterHandle::shutdown`].
+#[derive(Debug)]
+pub struct ShutdownReport<T> {
+    /// What every thread that stopped returned, by process id.
+    pub finished: BTreeMap<usize, Option<T>>,
+    pub panicked: BTreeSet<usize>,
+    /// Threads still running at the deadline. They are left detached.
+    pub stragglers: BTreeSet<usize>,
+}
+
+impl<T> ShutdownReport<T> {
+    pub fn is_clean(&self) -> bool {
+        self.panicked.is_empty() && self.stragglers.is_empty()
+    }
+}
+
+impl<T> Default for ClusterHandle<T>
+where
+    T: Send + 'static,
+{
+    fn default() -> Self {
+        ClusterHandle::new()
+    }
+}
+
+impl<T> ClusterHandle<T>
+where
+    T: Send + 'static,
+{
+    pub fn new() -> ClusterHandle<T> {
+        ClusterHandle {
+            cancel: CancellationToken::new(),
+            workers: BTreeMap::new(),
+        }
+    }
+
+    /// Token cancelled on shutdown, to be passed to every process through
+    /// [`NetworkInfo::with_cancellation`](crate::util::NetworkInfo::with_cancellation).
+    pub fn token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn spawn(&mut self, id: usize, run: impl FnOnce() -> Option<T> + Send + 'static) {
+        let handle = thread::Builder::new()
+            .name(format!("process-{id}"))
+            .spawn(run)
+            .expect("Expected to be able to spawn a process thread");
+        let previous = self.workers.insert(id, handle);
+        assert!(
+            previous.is_none(),
+            "Expected process {id} to be spawned once"
+        );
+    }
+
+    /// Waits for process `id` to return, propagating its panic if it had one.
+    pub fn join(&mut self, id: usize) -> Option<T> {
+        let handle = self
+            .workers
+            .remove(&id)
+            .unwrap_or_else(|| panic!("Expected process {id} to be running"));
+        handle
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Cancels every process and joins the threads that stop within `deadline`.
+    pub fn shutdown(self, deadline: Duration) -> ShutdownReport<T> {
+        self.cancel.cancel();
+        let end = Instant::now() + deadline;
+        while Instant::now() < end && self.workers.values().any(|handle| !handle.is_finished()) {
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let mut report = ShutdownReport {
+            finished: BTreeMap::new(),
+            panicked: BTreeSet::new(),
+            stragglers: BTreeSet::new(),
+        };
+        for (id, handle) in self.workers {
+            if !handle.is_finished() {
+                report.stragglers.insert(id);
+                continue;
+            }
+            match handle.join() {
+                Ok(result) => {
+                    report.finished.insert(id, result);
+                }
+                Err(_) => {
+                    report.panicked.insert(id);
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        broadcast::BroadcastValue,
+        faulty,
+        network::{self, ChannelConfig},
+        util::NetworkInfo,
+    };
+
+    #[test]
+    fn shutdown_stops_processes_that_never_decide() {
+        let process_count = 4;
+        let endpoints = network::connect(process_count, ChannelConfig::for_cluster(process_count));
+        let mut cluster: ClusterHandle<bool> = ClusterHandle::new();
+        for (id, (senders, inbox)) in endpoints.into_iter().enumerate() {
+            let network = NetworkInfo::new(id, senders, inbox).with_cancellation(cluster.token());
+            cluster.spawn(id, move || {
+                faulty::faulty_process(BroadcastValue::new(true, false), network);
+                None
+            });
+        }
+
+        let report = cluster.shutdown(Duration::from_secs(5));
+        assert!(report.is_clean(), "{report:?}");
+        assert_eq!(report.finished.len(), process_count);
+    }
+
+    #[test]
+    fn shutdown_reports_stragglers() {
+        let mut cluster = ClusterHandle::new();
+        cluster.spawn(0, || Some(true));
+        cluster.spawn(1, || {
+            thread::sleep(Duration::from_secs(2));
+            None
+        });
+        cluster.spawn(2, || panic!("Expected to fail"));
+
+        let report = cluster.shutdown(Duration::from_millis(200));
+        assert_eq!(report.finished, BTreeMap::from([(0, Some(true))]));
+        assert_eq!(report.panicked, BTreeSet::from([2]));
+        assert_eq!(report.stragglers, BTreeSet::from([1]));
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use crate::{
+    broadcast::BroadcastValue,
+    messaging::{Message, MessageType},
+    trace::{Trace, TraceEvent},
+};
+
+/// Parts of a [`Trace`] a diagram shows, every round, process or message type being kept when
+/// the corresponding set is empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub rounds: BTreeSet<usize>,
+    /// Processes to show, along with the messages between them.
+    pub nodes: BTreeSet<usize>,
+    pub message_types: BTreeSet<MessageType>,
+}
+
+impl TraceFilter {
+    fn keeps_round(&self, round: usize) -> bool {
+        self.rounds.is_empty() || self.rounds.contains(&round)
+    }
+
+    fn keeps_node(&self, node: usize) -> bool {
+        self.nodes.is_empty() || self.nodes.contains(&node)
+    }
+
+    fn keeps(&self, from: usize, to: usize, message: &Message<bool>) -> bool {
+        self.keeps_node(from)
+            && self.keeps_node(to)
+            && self.keeps_round(message.round)
+            && (self.message_types.is_empty() || self.message_types.contains(&message.message_type))
+    }
+}
+
+/// Group of messages drawn as one sequence diagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Instance {
+    Broadcast {
+        round: usize,
+        source: usize,
+    },
+    /// Decision announcements, which belong to no broadcast.
+    Decisions,
+}
+
+impl Instance {
+    fn title(&self) -> String {
+        match self {
+            Instance::Broadcast { round, source } => {
+                format!("Round {round}, broadcast of process {source}")
+            }
+            Instance::Decisions => "Decision announcements".to_string(),
+        }
+    }
+}
+
+type Delivery<'a> = (usize, usize, &'a Message<bool>);
+
+/// Kept deliveries by instance, each in delivery order.
+fn deliveries<'a>(trace: &'a Trace, filter: &TraceFilter) -> BTreeMap<Instance, Vec<Delivery<'a>>> {
+    let mut instances: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for event in &trace.events {
+        let TraceEvent::Deliver {
+            from, to, message, ..
+        } = event
+        else {
+            continue;
+        };
+        if !filter.keeps(*from, *to, message) {
+            continue;
+        }
+        let instance = match message.message_type {
+            MessageType::Decide => Instance::Decisions,
+            _ => Instance::Broadcast {
+                round: message.round,
+                source: message.broadcast_source_id,
+            },
+        };
+        instances
+            .entry(instance)
+            .or_default()
+            .push((*from, *to, message));
+    }
+    instances
+}
+
+fn value_label(value: &BroadcastValue<bool>) -> String {
+    match value.decided {
+        true => format!("{} (decided)", value.value),
+        false => value.value.to_string(),
+    }
+}
+
+fn message_label(message: &Message<bool>) -> String {
+    format!("{:?} {}", message.message_type, value_label(&message.value))
+}
+
+/// A Markdown section per broadcast instance, with its deliveries as a Mermaid sequence diagram.
+pub fn mermaid(trace: &Trace, filter: &TraceFilter) -> String {
+    let mut output = String::new();
+    for (instance, deliveries) in deliveries(trace, filter) {
+        writeln!(
+            output,
+            "### {}\n\n```mermaid\nsequenceDiagram",
+            instance.title()
+        )
+        .unwrap();
+        for node in (0..trace.process_count).filter(|node| filter.keeps_node(*node)) {
+            writeln!(output, "    participant P{node}").unwrap();
+        }
+        for (from, to, message) in deliveries {
+            writeln!(output, "    P{from}->>P{to}: {}", message_label(message)).unwrap();
+        }
+        writeln!(output, "```\n").unwrap();
+    }
+    output
+}
+
+/// A PlantUML sequence diagram per broadcast instance, with its deliveries.
+pub fn plantuml(trace: &Trace, filter: &TraceFilter) -> String {
+    let mut output = String::new();
+    for (instance, deliveries) in deliveries(trace, filter) {
+        writeln!(output, "@startuml\ntitle {}", instance.title()).unwrap();
+        for node in (0..trace.process_count).filter(|node| filter.keeps_node(*node)) {
+            writeln!(output, "participant P{node}").unwrap();
+        }
+        for (from, to, message) in deliveries {
+            writeln!(output, "P{from} -> P{to} : {}", message_label(message)).unwrap();
+        }
+        writeln!(output, "@enduml\n").unwrap();
+    }
+    output
+}
+
+/// A Graphviz graph of the rounds each honest process went through, grouped by phase, with the
+/// value it started each round with, its coin flips and its decision.
+///
+/// A process enters a round when it initiates its own broadcast in it. Message types are not
+/// filtered, as rounds are not made of a single type.
+pub fn dot(trace: &Trace, filter: &TraceFilter) -> String {
+    let mut rounds: BTreeMap<usize, BTreeMap<usize, &BroadcastValue<bool>>> = BTreeMap::new();
+    let mut coins = BTreeMap::new();
+    let mut decisions = BTreeMap::new();
+    for event in &trace.events {
+        match event {
+            TraceEvent::Start { node, .. } if filter.keeps_node(*node) => {
+                rounds.insert(*node, BTreeMap::new());
+            }
+            TraceEvent::Send {
+                from, to, message, ..
+            } if from == to
+                && message.broadcast_source_id == *from
+                && message.message_type == MessageType::Initiate =>
+            {
+                if let Some(entered) = rounds.get_mut(from) {
+                    entered.entry(message.round).or_insert(&message.value);
+                }
+            }
+            TraceEvent::Coin { node, value, .. } => {
+                if let Some(round) = current_round(&rounds, *node) {
+                    coins.insert((*node, round), *value);
+                }
+            }
+            TraceEvent::Decide { node, value, .. } => {
+                if let Some(round) = current_round(&rounds, *node) {
+                    decisions.insert(*node, (round, *value));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut phases: BTreeMap<usize, BTreeMap<usize, Vec<String>>> = BTreeMap::new();
+    let mut statements = Vec::new();
+    for (node, entered) in &rounds {
+        let kept: Vec<_> = entered
+            .iter()
+            .filter(|(round, _)| filter.keeps_round(**round))
+            .collect();
+        for (round, value) in &kept {
+            phases
+                .entry(**round / 3)
+                .or_default()
+                .entry(**round)
+                .or_default()
+                .push(format!(
+                    "\"p{node}_r{round}\" [label=\"P{node} round {round}\\n{}\"];",
+                    value_label(value)
+                ));
+        }
+        for pair in kept.windows(2) {
+            let (round, next) = (pair[0].0, pair[1].0);
+            let label = match coins.get(&(*node, *round)) {
+                Some(coin) => format!(" [label=\"coin {coin}\"]"),
+                None => String::new(),
+            };
+            statements.push(format!(
+                "\"p{node}_r{round}\" -> \"p{node}_r{next}\"{label};"
+            ));
+        }
+        if let Some((round, value)) = decisions.get(node) {
+            if filter.keeps_round(*round) {
+                statements.push(format!(
+                    "\"p{node}_decided\" [label=\"P{node} decided {value}\", shape=doubleoctagon];"
+                ));
+                statements.push(format!("\"p{node}_r{round}\" -> \"p{node}_decided\";"));
+            }
+        }
+    }
+
+    let mut output =
+        String::from("digraph progression {\n    rankdir=LR;\n    node [shape=box];\n");
+    for (phase, rounds) in phases {
+        writeln!(output, "    subgraph cluster_phase_{phase} {{").unwrap();
+        writeln!(output, "        label=\"phase {phase}\";").unwrap();
+        for nodes in rounds.values() {
+            writeln!(output, "        {{ rank=same; {} }}", nodes.join(" ")).unwrap();
+        }
+        writeln!(output, "    }}").unwrap();
+    }
+    for statement in statements {
+        writeln!(output, "    {statement}").unwrap();
+    }
+    output.push_str("}\n");
+    output
+}
+
+fn current_round(
+    rounds: &BTreeMap<usize, BTreeMap<usize, &BroadcastValue<bool>>>,
+    node: usize,
+) -> Option<usize> {
+    rounds.get(&node)?.keys().next_back().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::simulation::{
+        self, Coin, Fault, InitialValues, NetworkConditions, Runtime, SimulationConfig,
+    };
+
+    fn trace() -> Trace {
+        let config = SimulationConfig {
+            process_count: 4,
+            faults: vec![Fault::Silent],
+            initial_values: InitialValues::Split,
+            coin: Coin::Common,
+            runtime: Runtime::Simulator,
+            seed: 1,
+            timeout: Duration::from_secs(30),
+            network: NetworkConditions::default(),
+        };
+        simulation::run_traced(&config).1
+    }
+
+    #[test]
+    fn sequence_diagrams_follow_the_filter() {
+        let trace = trace();
+        let all = mermaid(&trace, &TraceFilter::default());
+        assert!(all.contains("### Round 0, broadcast of process 0\n\n```mermaid\nsequenceDiagram"));
+        assert!(all.contains("    P1->>P2: Echo "), "{all}");
+        assert!(all.contains("### Decision announcements"));
+
+        let filter = TraceFilter {
+            rounds: BTreeSet::from([1]),
+            nodes: BTreeSet::from([0, 1]),
+            message_types: BTreeSet::from([MessageType::Ready]),
+        };
+        let filtered = plantuml(&trace, &filter);
+        assert!(filtered.starts_with("@startuml\ntitle Round 1, broadcast of process 0\n"));
+        assert!(filtered.contains("P0 -> P1 : Ready "), "{filtered}");
+        assert!(!filtered.contains("P2") && !filtered.contains("Echo"));
+        assert!(!filtered.contains("Round 0"));
+    }
+
+    #[test]
+    fn progression_graphs_show_rounds_and_decisions() {
+        let trace = trace();
+        let graph = dot(&trace, &TraceFilter::default());
+        assert!(graph.starts_with("digraph progression {"));
+        assert!(graph.contains("subgraph cluster_phase_0"));
+        assert!(graph.contains("\"p0_r0\" -> \"p0_r1\";"), "{graph}");
+        assert!(graph.contains("\"p0_decided\""));
+        // The silent process never enters a round
+        assert!(!graph.contains("p3_r0"));
+
+        let filter = TraceFilter {
+            rounds: BTreeSet::from([0]),
+            nodes: BTreeSet::from([1]),
+            ..TraceFilter::default()
+        };
+        let graph = dot(&trace, &filter);
+        assert!(graph.contains("\"p1_r0\"") && !graph.contains("p1_r1") && !graph.contains("p0_"));
+    }
+}
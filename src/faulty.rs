@@ -1,34 +1,83 @@
-use crossbeam::channel::Receiver;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 
-use crate::{broadcast::{BroadcastSender, BroadcastValue}, messaging::{Message, MessageType}, util::{Broadcastable, NetworkInfo}};
+use crate::{
+    broadcast::{BroadcastSender, BroadcastValue},
+    messaging::{Message, MessageType},
+    util::{Broadcastable, NetworkInfo},
+};
 
+/// Kinds of message a faulty process sends for each source of a round it hears of.
+pub(crate) const MESSAGE_TYPES: [MessageType; 4] = [
+    MessageType::Initiate,
+    MessageType::Echo,
+    MessageType::Ready,
+    MessageType::Decide,
+];
 
-
-
-
-pub fn faulty_process<T>(repeated_value: BroadcastValue<T>, network: NetworkInfo<T>) -> bool
-where T: Broadcastable
+/// Sends `repeated_value` to every process for every broadcast of every round it hears of, and
+/// announces it as decided, until cancelled.
+pub fn faulty_process<T>(repeated_value: BroadcastValue<T>, network: NetworkInfo<T>)
+where
+    T: Broadcastable,
 {
-
-
     let mut round_count = 0;
-    let receiver = network.receiver;
+    let mut inbox = network.inbox;
+    let cancel = network.cancel;
     let process_count = network.senders.len();
 
-    let sender = BroadcastSender::new(network.senders);
-
-    loop {
-        let message = receiver.recv().unwrap();
+    let sender = BroadcastSender::new(network.id, network.senders);
 
+    while let Some((_, message)) = inbox.recv(&cancel) {
         if message.round == round_count {
+            for source in 0..process_count {
+                for message_type in MESSAGE_TYPES {
+                    sender.send(Message::new(
+                        message.round,
+                        sender.id(),
+                        source,
+                        repeated_value.clone(),
+                        message_type,
+                    ));
+                }
+            }
+            round_count += 1;
+        }
+    }
+}
 
-            for id in 0..process_count {
-                sender.send(Message::new(message.round, id, repeated_value.clone(), MessageType::Initiate));
-                sender.send(Message::new(message.round, id, repeated_value.clone(), MessageType::Echo));
-                sender.send(Message::new(message.round, id, repeated_value.clone(), MessageType::Ready));
+/// Like [`faulty_process`], but tells every process something different: each message to each
+/// recipient carries one of `values` picked at random from `seed`.
+pub fn equivocating_process<T>(values: Vec<BroadcastValue<T>>, seed: u64, network: NetworkInfo<T>)
+where
+    T: Broadcastable,
+{
+    let mut round_count = 0;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let NetworkInfo {
+        id,
+        senders,
+        mut inbox,
+        cancel,
+        ..
+    } = network;
+
+    while let Some((_, message)) = inbox.recv(&cancel) {
+        if message.round == round_count {
+            for recipient in &senders {
+                for source in 0..senders.len() {
+                    for message_type in MESSAGE_TYPES {
+                        let value = values.choose(&mut rng).unwrap().clone();
+                        recipient.send(Message::new(
+                            message.round,
+                            id,
+                            source,
+                            value,
+                            message_type,
+                        ));
+                    }
+                }
             }
             round_count += 1;
         }
     }
-    false
-}
\ No newline at end of file
+}
@@ -0,0 +1,26 @@
+pub mod acs;
+pub mod async_protocol;
+pub mod auth;
+pub mod broadcast;
+pub mod byz_protocol;
+pub mod cluster;
+pub mod diagram;
+pub mod faulty;
+pub mod messaging;
+pub mod metrics;
+pub mod model_check;
+pub mod network;
+pub mod node;
+pub mod phase;
+pub mod quorum;
+pub mod replicated_log;
+pub mod round;
+pub mod scenario;
+pub mod selection_protocol;
+pub mod simulation;
+pub mod smr;
+pub mod sweep;
+pub mod trace;
+pub mod transport;
+pub mod util;
+pub mod validation;
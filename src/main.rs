@@ -1,70 +1,358 @@
-use std::thread;
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, IsTerminal},
+    path::PathBuf,
+    process, thread,
+    time::Duration,
+};
 
-use crossbeam::channel;
-use rand::Rng;
-use util::{Broadcastable, NetworkInfo};
+use async_byz_consensus::{
+    diagram::{self, TraceFilter},
+    messaging::MessageType,
+    scenario::{Expectations, Scenario},
+    simulation::{self, Coin, Fault, InitialValues, NetworkConditions, Runtime, SimulationConfig},
+    sweep::{self, Sweep},
+    trace::{self, Trace},
+    util,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
 
-use crate::broadcast::BroadcastValue;
+/// Runs binary consensus among honest and faulty processes and reports what they decided.
+#[derive(Debug, Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+struct Options {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Scenario file to run instead of the options below, exiting with a non-zero status if
+    /// its expectations fail.
+    #[arg(
+        long,
+        conflicts_with_all = ["nodes", "faulty", "faults", "initial", "coin", "transport", "seed", "timeout", "max_time"]
+    )]
+    scenario: Option<PathBuf>,
+    /// Number of processes.
+    #[arg(long, default_value_t = 100)]
+    nodes: usize,
+    /// Number of faulty processes, at most a third of them by default.
+    #[arg(long)]
+    faulty: Option<usize>,
+    /// Behaviour of each faulty process in turn, the last one applying to the remaining ones:
+    /// silent, equivocate or repeat:<value>[:decided].
+    #[arg(long = "fault", default_value = "repeat:false:decided")]
+    faults: Vec<Fault>,
+    /// Initial values of the honest processes: same:<value>, split or random.
+    #[arg(long, default_value = "same:true")]
+    initial: InitialValues,
+    #[arg(long, value_enum, default_value_t = Coin::Local)]
+    coin: Coin,
+    #[arg(long, value_enum, default_value_t = Runtime::Threads)]
+    transport: Runtime,
+    /// Seed of the random initial values, coins, faults and simulated delivery order.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Seconds the honest processes get to decide with the threads transport.
+    #[arg(long, default_value_t = 60)]
+    timeout: u64,
+    /// Virtual ticks after which the simulator stops.
+    #[arg(long, default_value_t = NetworkConditions::default().max_time)]
+    max_time: u64,
+    #[arg(long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+    /// File to record every message sent and delivered to, for the replay subcommand. Requires
+    /// the simulator.
+    #[arg(long)]
+    trace: Option<PathBuf>,
+    #[command(flatten)]
+    log: LogOptions,
+}
+
+#[derive(Debug, Args)]
+struct LogOptions {
+    /// Level of the protocol logs written to the standard error: off, error, warn, info, debug
+    /// or trace.
+    #[arg(long = "log", global = true, default_value_t = LevelFilter::OFF)]
+    log_level: LevelFilter,
+    /// Processes to log, separated by commas, every one by default.
+    #[arg(long = "log-node", global = true, value_delimiter = ',')]
+    log_nodes: Vec<usize>,
+}
 
-mod broadcast;
-mod byz_protocol;
-mod faulty;
-mod messaging;
-mod phase;
-mod round;
-mod selection_protocol;
-mod util;
-mod validation;
+impl LogOptions {
+    fn filter(&self) -> EnvFilter {
+        let crate_name = env!("CARGO_CRATE_NAME");
+        let directives: Vec<_> = match self.log_nodes.is_empty() {
+            true => vec![format!("{crate_name}={}", self.log_level)],
+            false => self
+                .log_nodes
+                .iter()
+                .map(|id| format!("{crate_name}[node{{id={id}}}]={}", self.log_level))
+                .collect(),
+        };
+        EnvFilter::builder()
+            .with_default_directive(LevelFilter::OFF.into())
+            .parse_lossy(directives.join(","))
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Runs the simulator over a grid of parameters and seeds, and writes the distribution of
+    /// phases and messages per decision at each point as CSV.
+    Sweep(SweepOptions),
+    /// Replays a trace recorded with --trace into fresh processes, and prints their states.
+    /// Exits with a non-zero status if they behave differently from the trace.
+    Replay {
+        /// Trace file.
+        trace: PathBuf,
+    },
+    /// Draws a trace recorded with --trace: a sequence diagram of each broadcast instance, or a
+    /// graph of the rounds each honest process went through.
+    Diagram(DiagramOptions),
+}
+
+#[derive(Debug, Args)]
+struct DiagramOptions {
+    /// Trace file.
+    trace: PathBuf,
+    #[arg(long, value_enum, default_value_t = DiagramFormat::Mermaid)]
+    format: DiagramFormat,
+    /// Rounds to show, separated by commas, every one by default.
+    #[arg(long = "round", value_delimiter = ',')]
+    rounds: Vec<usize>,
+    /// Processes to show, separated by commas, every one by default.
+    #[arg(long = "node", value_delimiter = ',')]
+    nodes: Vec<usize>,
+    /// Message types to show, separated by commas, every one by default: initiate, echo, ready
+    /// or decide.
+    #[arg(long = "type", value_delimiter = ',')]
+    message_types: Vec<MessageType>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DiagramFormat {
+    /// Markdown with a Mermaid sequence diagram per broadcast instance.
+    Mermaid,
+    /// A PlantUML sequence diagram per broadcast instance.
+    Plantuml,
+    /// A Graphviz graph of the round progression.
+    Dot,
+}
+
+#[derive(Debug, Args)]
+struct SweepOptions {
+    /// Process counts, separated by commas.
+    #[arg(long, value_delimiter = ',', required = true)]
+    nodes: Vec<usize>,
+    /// Faulty process counts, separated by commas, at most a third of the processes by default.
+    #[arg(long, value_delimiter = ',')]
+    faulty: Vec<usize>,
+    /// Behaviour of every faulty process: silent, equivocate or repeat:<value>[:decided].
+    #[arg(long, default_value = "repeat:false:decided")]
+    fault: Fault,
+    /// Initial values of the honest processes, separated by commas: same:<value>, split or
+    /// random.
+    #[arg(long, value_delimiter = ',', default_value = "split")]
+    initial: Vec<InitialValues>,
+    #[arg(long, value_enum, default_value_t = Coin::Local)]
+    coin: Coin,
+    /// Runs per point, with seeds from 0.
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u64).range(1..))]
+    seeds: u64,
+    /// Virtual ticks after which each run stops.
+    #[arg(long, default_value_t = NetworkConditions::default().max_time)]
+    max_time: u64,
+    /// Simulations running at once, one per available CPU by default.
+    #[arg(long)]
+    threads: Option<usize>,
+    /// File to write the CSV to instead of the standard output.
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Output {
+    Table,
+    Json,
+    Csv,
+}
+
+impl Options {
+    fn config(&self) -> SimulationConfig {
+        let faulty = self
+            .faulty
+            .unwrap_or_else(|| util::faulty_count(self.nodes));
+        let last = self.faults.last().cloned().unwrap_or(Fault::Silent);
+        let faults = (0..faulty)
+            .map(|index| self.faults.get(index).unwrap_or(&last).clone())
+            .collect();
+        SimulationConfig {
+            process_count: self.nodes,
+            faults,
+            initial_values: self.initial,
+            coin: self.coin,
+            runtime: self.transport,
+            seed: self.seed,
+            timeout: Duration::from_secs(self.timeout),
+            network: NetworkConditions {
+                max_time: self.max_time,
+                ..NetworkConditions::default()
+            },
+        }
+    }
+}
 
-//TODO do threads terminate after deciding or only at end of the phase? Or only when everyone has decided?
 fn main() {
-    let process_count = 100;
-    let faulty_count = util::faulty_count(process_count);
-    let good_count = process_count - faulty_count;
-
-    let mut senders = Vec::with_capacity(process_count);
-    let mut receivers = Vec::with_capacity(process_count);
-    for process_id in 0..process_count {
-        let (s, r) = channel::unbounded();
-        senders.push(s);
-        receivers.push(r);
+    let options = Options::parse();
+    tracing_subscriber::fmt()
+        .with_env_filter(options.log.filter())
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+    match options.command {
+        Some(Command::Sweep(ref sweep)) => run_sweep(sweep),
+        Some(Command::Replay { ref trace }) => replay(trace),
+        Some(Command::Diagram(ref diagram)) => draw(diagram),
+        None => run(&options),
     }
+}
 
-    let mut join_handles = Vec::with_capacity(process_count);
-    for process_id in 0..process_count {
-        let senders_clone = senders.clone();
-        let receiver = receivers.pop().unwrap();
-        join_handles.push(thread::spawn(move || {
-            byz_protocol::consensus_protocol(
-                true,
-                // random_boolean(),
-                random_boolean,
-                NetworkInfo::new(process_id, senders_clone, receiver),
-            )
-        }))
+fn run(options: &Options) {
+    let (config, expect) = match &options.scenario {
+        Some(path) => {
+            let scenario = Scenario::load(path).unwrap_or_else(|error| {
+                eprintln!("{error}");
+                process::exit(2);
+            });
+            (scenario.config(), scenario.expect)
+        }
+        None => (options.config(), Expectations::default()),
+    };
+    if let Err(error) = config.validate() {
+        eprintln!("{error}");
+        process::exit(2);
     }
-    for process_id in process_count..process_count + faulty_count {
-        let senders_clone = senders.clone();
-
-        if let Some(receiver) = receivers.pop() {
-            join_handles.push(thread::spawn(move || {
-                faulty::faulty_process(
-                    BroadcastValue::new(false, true),
-                    NetworkInfo::new(process_id, senders_clone, receiver),
-                )
-            }));
+
+    let report = match &options.trace {
+        Some(path) => {
+            if config.runtime != Runtime::Simulator {
+                eprintln!("Traces are only recorded by the simulator, see --transport");
+                process::exit(2);
+            }
+            let (report, trace) = simulation::run_traced(&config);
+            let written = File::create(path).and_then(|file| trace.write_to(BufWriter::new(file)));
+            if let Err(error) = written {
+                eprintln!("Cannot write {}: {error}", path.display());
+                process::exit(2);
+            }
+            report
         }
+        None => simulation::run(&config),
+    };
+    match options.output {
+        Output::Table => println!("{report}"),
+        Output::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Expected the report to serialize")
+        ),
+        Output::Csv => print!("{}", report.to_csv()),
+    }
+    if !report.stragglers.is_empty() {
+        eprintln!("Processes still running: {:?}", report.stragglers);
+    }
+    let failures = expect.failures(&report);
+    for failure in &failures {
+        eprintln!("{failure}");
+    }
+    if !failures.is_empty() {
+        process::exit(1);
     }
+}
 
-    for handle in join_handles.into_iter() {
-        let result = handle.join().unwrap();
-        println!("Agreed on {result}");
+fn run_sweep(options: &SweepOptions) {
+    let sweep = Sweep {
+        nodes: options.nodes.clone(),
+        faulty: options.faulty.clone(),
+        fault: options.fault.clone(),
+        initial_values: options.initial.clone(),
+        coin: options.coin,
+        seeds: options.seeds,
+        network: NetworkConditions {
+            max_time: options.max_time,
+            ..NetworkConditions::default()
+        },
+    };
+    let threads = options
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |threads| threads.get()));
+    for (nodes, faulty) in sweep.skipped() {
+        eprintln!(
+            "Skipping {faulty} faulty processes: at most {} of {nodes} processes can be faulty",
+            util::faulty_count(nodes)
+        );
+    }
+    if let Err(error) = sweep.validate() {
+        eprintln!("{error}");
+        process::exit(2);
     }
-    println!("Done");
+    let rows = sweep.run(threads).expect("Expected a valid sweep");
+    let csv = sweep::to_csv(&rows);
+    match &options.output {
+        Some(path) => fs::write(path, csv).unwrap_or_else(|error| {
+            eprintln!("Cannot write {}: {error}", path.display());
+            process::exit(2);
+        }),
+        None => print!("{csv}"),
+    }
+}
+
+fn read_trace(path: &PathBuf) -> Result<Trace, String> {
+    File::open(path)
+        .map_err(|error| format!("Cannot read {}: {error}", path.display()))
+        .and_then(|file| Trace::read_from(BufReader::new(file)))
 }
 
-impl Broadcastable for bool {}
+fn replay(path: &PathBuf) {
+    let nodes = read_trace(path).and_then(|trace| trace::replay(&trace));
+    let nodes = nodes.unwrap_or_else(|error| {
+        eprintln!("{error}");
+        process::exit(1);
+    });
+    for node in nodes.values() {
+        let state = node.state();
+        match state.decided() {
+            Some(value) => println!(
+                "Process {} decided {value}, at round {} of phase {}, terminated: {}",
+                node.id(),
+                state.round(),
+                state.phase(),
+                node.terminated()
+            ),
+            None => println!(
+                "Process {} holds {:?} at round {} of phase {}",
+                node.id(),
+                state.value(),
+                state.round(),
+                state.phase()
+            ),
+        }
+    }
+}
 
-fn random_boolean() -> bool {
-    rand::thread_rng().gen_bool(0.5)
+fn draw(options: &DiagramOptions) {
+    let trace = read_trace(&options.trace).unwrap_or_else(|error| {
+        eprintln!("{error}");
+        process::exit(2);
+    });
+    let filter = TraceFilter {
+        rounds: options.rounds.iter().copied().collect(),
+        nodes: options.nodes.iter().copied().collect(),
+        message_types: options.message_types.iter().copied().collect(),
+    };
+    let output = match options.format {
+        DiagramFormat::Mermaid => diagram::mermaid(&trace, &filter),
+        DiagramFormat::Plantuml => diagram::plantuml(&trace, &filter),
+        DiagramFormat::Dot => diagram::dot(&trace, &filter),
+    };
+    print!("{output}");
 }
@@ -1,30 +1,101 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
 use crate::broadcast::BroadcastValue;
 
+/// Messages received for rounds the local process has not reached yet, keyed by round.
+pub type EarlyMessages<T> = BTreeMap<usize, Vec<Message<T>>>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct Message<T> {
+    /// Consensus instance the message belongs to, see [`replicated_log`](crate::replicated_log).
+    pub slot: usize,
     pub round: usize,
+    pub sender_id: usize,
     pub broadcast_source_id: usize,
     pub message_type: MessageType,
     pub value: BroadcastValue<T>,
 }
 
 impl<T> Message<T> {
-    pub fn new(round: usize, broadcast_source_id: usize, value:BroadcastValue<T>, message_type: MessageType) -> Message<T> {
+    pub fn new(
+        round: usize,
+        sender_id: usize,
+        broadcast_source_id: usize,
+        value: BroadcastValue<T>,
+        message_type: MessageType,
+    ) -> Message<T> {
         Message {
+            slot: 0,
             round,
+            sender_id,
             broadcast_source_id,
             message_type,
             value,
         }
     }
+
+    pub fn with_slot(self, slot: usize) -> Message<T> {
+        Message { slot, ..self }
+    }
 }
 
+impl<T> Message<T>
+where
+    T: Serialize,
+{
+    /// Bytes sent over the wire for this message.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Expected messages to serialize")
+    }
+}
 
+impl<T> Message<T>
+where
+    T: DeserializeOwned,
+{
+    /// Reads a message written by [`Message::encode`].
+    pub fn decode(bytes: &[u8]) -> Result<Message<T>, String> {
+        serde_json::from_slice(bytes).map_err(|error| format!("Invalid message: {error}"))
+    }
+}
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub enum MessageType {
     Initiate,
     Echo,
     Ready,
-}
\ No newline at end of file
+    /// Announces the value decided by the sender. Not part of any broadcast instance.
+    Decide,
+}
+
+impl fmt::Display for MessageType {
+    /// Writes the lowercase type name that [`MessageType::from_str`] parses.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MessageType::Initiate => "initiate",
+            MessageType::Echo => "echo",
+            MessageType::Ready => "ready",
+            MessageType::Decide => "decide",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for MessageType {
+    type Err = String;
+
+    /// Parses a lowercase type name, such as `echo`.
+    fn from_str(text: &str) -> Result<MessageType, String> {
+        match text {
+            "initiate" => Ok(MessageType::Initiate),
+            "echo" => Ok(MessageType::Echo),
+            "ready" => Ok(MessageType::Ready),
+            "decide" => Ok(MessageType::Decide),
+            _ => Err(format!(
+                "Unknown message type {text}, expected initiate, echo, ready or decide"
+            )),
+        }
+    }
+}
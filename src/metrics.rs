@@ -0,0 +1,537 @@
+use std::{
+    fmt::{Debug, Write as _},
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    messaging::{Message, MessageType},
+    transport::Transport,
+    util::CancellationToken,
+};
+
+/// How long the metrics server waits for a scraper to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something a process reports to a [`MetricsSink`]. More events may be reported in the future.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetricEvent {
+    /// A message was broadcast to every process.
+    Sent(MessageType),
+    Received(MessageType),
+    /// Size of an encoded message broadcast to every process, counted once.
+    BytesSent(usize),
+    BytesReceived(usize),
+    /// The process decided while in `round` of `phase`.
+    Decided {
+        round: usize,
+        phase: usize,
+    },
+    /// Delivered values that failed their first validation attempt.
+    Rejected(usize),
+    /// Messages now buffered for rounds the process has not reached yet.
+    EarlyMessages(usize),
+    CoinFlip,
+    /// A received frame failed authentication and was dropped.
+    Unauthenticated,
+}
+
+/// Destination of the [`MetricEvent`]s of every process of a deployment, by process id.
+pub trait MetricsSink: Send + Sync + Debug {
+    fn record(&self, node: usize, event: MetricEvent);
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Counter,
+    Gauge,
+    /// Upper bounds of the buckets, the last one being implicitly infinite.
+    Histogram(&'static [u64]),
+}
+
+/// Metric exposed under one name, with a sample per process, and per message type if
+/// `by_type` is set.
+#[derive(Debug)]
+struct Family {
+    /// Position in [`FAMILIES`].
+    index: usize,
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    by_type: bool,
+}
+
+const MESSAGES_SENT: Family = Family {
+    index: 0,
+    name: "consensus_messages_sent_total",
+    help: "Messages broadcast, by type.",
+    kind: Kind::Counter,
+    by_type: true,
+};
+const MESSAGES_RECEIVED: Family = Family {
+    index: 1,
+    name: "consensus_messages_received_total",
+    help: "Messages received, by type.",
+    kind: Kind::Counter,
+    by_type: true,
+};
+const BYTES_SENT: Family = Family {
+    index: 2,
+    name: "consensus_bytes_sent_total",
+    help: "Bytes of the encoded messages broadcast, counted once per broadcast.",
+    kind: Kind::Counter,
+    by_type: false,
+};
+const BYTES_RECEIVED: Family = Family {
+    index: 3,
+    name: "consensus_bytes_received_total",
+    help: "Bytes of the encoded messages received.",
+    kind: Kind::Counter,
+    by_type: false,
+};
+const ROUNDS: Family = Family {
+    index: 4,
+    name: "consensus_rounds_per_decision",
+    help: "Rounds started before deciding.",
+    kind: Kind::Histogram(&[3, 6, 9, 12, 15, 30, 60, 120]),
+    by_type: false,
+};
+const PHASES: Family = Family {
+    index: 5,
+    name: "consensus_phases_per_decision",
+    help: "Phases started before deciding.",
+    kind: Kind::Histogram(&[1, 2, 3, 4, 5, 10, 20, 40]),
+    by_type: false,
+};
+const REJECTED: Family = Family {
+    index: 6,
+    name: "consensus_validation_rejections_total",
+    help: "Delivered values that failed their first validation attempt.",
+    kind: Kind::Counter,
+    by_type: false,
+};
+const EARLY_MESSAGES: Family = Family {
+    index: 7,
+    name: "consensus_early_messages",
+    help: "Messages buffered for rounds not reached yet.",
+    kind: Kind::Gauge,
+    by_type: false,
+};
+const COIN_FLIPS: Family = Family {
+    index: 8,
+    name: "consensus_coin_flips_total",
+    help: "Coin flips used to start a phase.",
+    kind: Kind::Counter,
+    by_type: false,
+};
+const UNAUTHENTICATED: Family = Family {
+    index: 9,
+    name: "consensus_unauthenticated_frames_total",
+    help: "Frames dropped for an unknown sender, an invalid MAC or a relayed message.",
+    kind: Kind::Counter,
+    by_type: false,
+};
+
+/// Every family, in the order they are exposed.
+const FAMILIES: [&Family; 10] = [
+    &MESSAGES_SENT,
+    &MESSAGES_RECEIVED,
+    &BYTES_SENT,
+    &BYTES_RECEIVED,
+    &ROUNDS,
+    &PHASES,
+    &REJECTED,
+    &EARLY_MESSAGES,
+    &COIN_FLIPS,
+    &UNAUTHENTICATED,
+];
+
+/// Types of the samples of families labelled by type, in declaration order, which is also
+/// their index as `usize`.
+const MESSAGE_TYPES: [MessageType; 4] = [
+    MessageType::Initiate,
+    MessageType::Echo,
+    MessageType::Ready,
+    MessageType::Decide,
+];
+
+/// Sample of a family for one label set, updated without locking.
+#[derive(Debug)]
+struct Sample {
+    /// Rendered once, when the registry is created.
+    labels: String,
+    /// Samples never updated are not exposed.
+    touched: AtomicBool,
+    /// Total of a counter, current value of a gauge, or sum of a histogram.
+    value: AtomicU64,
+    /// Cumulative bucket counts of a histogram, as exposed.
+    buckets: Vec<AtomicU64>,
+    /// Observations of a histogram.
+    count: AtomicU64,
+}
+
+impl Sample {
+    fn new(family: &Family, labels: String) -> Sample {
+        let bounds = match family.kind {
+            Kind::Histogram(bounds) => bounds.len(),
+            _ => 0,
+        };
+        Sample {
+            labels,
+            touched: AtomicBool::new(false),
+            value: AtomicU64::new(0),
+            buckets: (0..bounds).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+/// [`MetricsSink`] keeping counters, gauges and histograms in memory, labelled by process, and
+/// rendering them in the Prometheus text format. See [`serve`] to expose them over HTTP.
+///
+/// Every sample is an atomic allocated up front, so that processes record without locking or
+/// allocating. Events of processes beyond the registry's process count are ignored.
+#[derive(Debug)]
+pub struct Registry {
+    process_count: usize,
+    /// Samples of each family, by process then by message type.
+    samples: [Vec<Sample>; FAMILIES.len()],
+}
+
+impl Registry {
+    /// Registry for the processes `0..process_count`.
+    pub fn new(process_count: usize) -> Registry {
+        Registry {
+            process_count,
+            samples: FAMILIES.map(|family| {
+                debug_assert_eq!(FAMILIES[family.index].name, family.name);
+                let mut samples = Vec::new();
+                for node in 0..process_count {
+                    let labels = format!("node=\"{node}\"");
+                    if family.by_type {
+                        samples.extend(MESSAGE_TYPES.iter().map(|message_type| {
+                            Sample::new(family, format!("{labels},type=\"{message_type}\""))
+                        }));
+                    } else {
+                        samples.push(Sample::new(family, labels));
+                    }
+                }
+                samples
+            }),
+        }
+    }
+
+    fn update(&self, family: &Family, node: usize, message_type: Option<MessageType>, value: u64) {
+        if node >= self.process_count {
+            return;
+        }
+        let index = match message_type {
+            Some(message_type) => node * MESSAGE_TYPES.len() + message_type as usize,
+            None => node,
+        };
+        let sample = &self.samples[family.index][index];
+        match family.kind {
+            Kind::Counter => {
+                sample.value.fetch_add(value, Ordering::Relaxed);
+            }
+            Kind::Gauge => sample.value.store(value, Ordering::Relaxed),
+            Kind::Histogram(bounds) => {
+                for (bucket, bound) in sample.buckets.iter().zip(bounds) {
+                    if value <= *bound {
+                        bucket.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                sample.value.fetch_add(value, Ordering::Relaxed);
+                sample.count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        sample.touched.store(true, Ordering::Relaxed);
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for family in FAMILIES {
+            let name = family.name;
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram(_) => "histogram",
+            };
+            writeln!(
+                output,
+                "# HELP {name} {}\n# TYPE {name} {kind}",
+                family.help
+            )
+            .unwrap();
+            let family_samples = self.samples[family.index]
+                .iter()
+                .filter(|sample| sample.touched.load(Ordering::Relaxed));
+            for sample in family_samples {
+                let labels = &sample.labels;
+                let value = sample.value.load(Ordering::Relaxed);
+                let Kind::Histogram(bounds) = family.kind else {
+                    writeln!(output, "{name}{{{labels}}} {value}").unwrap();
+                    continue;
+                };
+                let count = sample.count.load(Ordering::Relaxed);
+                for (bound, bucket) in bounds.iter().zip(&sample.buckets) {
+                    let bucket = bucket.load(Ordering::Relaxed);
+                    writeln!(output, "{name}_bucket{{{labels},le=\"{bound}\"}} {bucket}").unwrap();
+                }
+                writeln!(output, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}").unwrap();
+                writeln!(output, "{name}_sum{{{labels}}} {value}").unwrap();
+                writeln!(output, "{name}_count{{{labels}}} {count}").unwrap();
+            }
+        }
+        output
+    }
+}
+
+impl MetricsSink for Registry {
+    fn record(&self, node: usize, event: MetricEvent) {
+        match event {
+            MetricEvent::Sent(message_type) => {
+                self.update(&MESSAGES_SENT, node, Some(message_type), 1)
+            }
+            MetricEvent::Received(message_type) => {
+                self.update(&MESSAGES_RECEIVED, node, Some(message_type), 1)
+            }
+            MetricEvent::BytesSent(bytes) => self.update(&BYTES_SENT, node, None, bytes as u64),
+            MetricEvent::BytesReceived(bytes) => {
+                self.update(&BYTES_RECEIVED, node, None, bytes as u64)
+            }
+            MetricEvent::Decided { round, phase } => {
+                self.update(&ROUNDS, node, None, round as u64 + 1);
+                self.update(&PHASES, node, None, phase as u64 + 1);
+            }
+            MetricEvent::Rejected(count) => self.update(&REJECTED, node, None, count as u64),
+            MetricEvent::EarlyMessages(count) => {
+                self.update(&EARLY_MESSAGES, node, None, count as u64)
+            }
+            MetricEvent::CoinFlip => self.update(&COIN_FLIPS, node, None, 1),
+            MetricEvent::Unauthenticated => self.update(&UNAUTHENTICATED, node, None, 1),
+        }
+    }
+}
+
+/// Local HTTP endpoint serving a [`Registry`] to Prometheus, see [`serve`].
+#[derive(Debug)]
+pub struct MetricsServer {
+    address: SocketAddr,
+    cancel: CancellationToken,
+    worker: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stops serving once the request being answered, if any, is done.
+    pub fn shutdown(self) {
+        self.cancel.cancel();
+        // Wakes the listener up so that it sees the cancellation
+        let _ = TcpStream::connect(self.address);
+        self.worker
+            .join()
+            .expect("Expected the metrics server not to panic");
+    }
+}
+
+/// Serves `registry` at `GET /metrics` on `address`, from a thread answering one request at a
+/// time. Other paths get a 404.
+pub fn serve(registry: Arc<Registry>, address: impl ToSocketAddrs) -> io::Result<MetricsServer> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    let worker = thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if token.is_cancelled() {
+                    break;
+                }
+                // Scrapers that hang up or misbehave only lose their own response
+                if let Ok(stream) = stream {
+                    let _ = respond(stream, &registry);
+                }
+            }
+        })?;
+    Ok(MetricsServer {
+        address,
+        cancel,
+        worker,
+    })
+}
+
+fn respond(stream: TcpStream, registry: &Registry) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are of no use, but are read so that the client sees a complete exchange
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", registry.render()),
+        _ => ("404 Not Found", "Not found, see /metrics\n".to_string()),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// [`Transport`] reporting the size of every message encoded through it, which processes
+/// themselves cannot tell.
+#[derive(Debug)]
+pub struct MeteredTransport<R> {
+    id: usize,
+    inner: R,
+    metrics: Arc<dyn MetricsSink>,
+}
+
+impl<R> MeteredTransport<R> {
+    /// Wraps the transport of process `id`.
+    pub fn new(id: usize, inner: R, metrics: Arc<dyn MetricsSink>) -> MeteredTransport<R> {
+        MeteredTransport { id, inner, metrics }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<T, R> Transport<T> for MeteredTransport<R>
+where
+    T: Serialize + Send,
+    R: Transport<T> + Send,
+{
+    type Error = R::Error;
+
+    async fn broadcast(&mut self, message: Message<T>) -> Result<(), R::Error> {
+        let bytes = message.encode().len();
+        self.inner.broadcast(message).await?;
+        self.metrics.record(self.id, MetricEvent::BytesSent(bytes));
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<(usize, Message<T>)>, R::Error> {
+        let received = self.inner.recv().await?;
+        if let Some((_, message)) = &received {
+            let bytes = message.encode().len();
+            self.metrics
+                .record(self.id, MetricEvent::BytesReceived(bytes));
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, io::Read};
+
+    use super::*;
+    use crate::{
+        node::ConsensusNode,
+        quorum::{QuorumSystem, ThresholdQuorum},
+    };
+
+    #[test]
+    fn registry_renders_prometheus_text() {
+        let registry = Registry::new(4);
+        registry.record(0, MetricEvent::Sent(MessageType::Echo));
+        registry.record(0, MetricEvent::Sent(MessageType::Echo));
+        registry.record(1, MetricEvent::EarlyMessages(5));
+        registry.record(1, MetricEvent::EarlyMessages(2));
+        registry.record(1, MetricEvent::Decided { round: 3, phase: 1 });
+
+        let text = registry.render();
+        assert!(text.contains(
+            "# HELP consensus_messages_sent_total Messages broadcast, by type.\n\
+             # TYPE consensus_messages_sent_total counter\n\
+             consensus_messages_sent_total{node=\"0\",type=\"echo\"} 2\n"
+        ));
+        assert!(text.contains("consensus_early_messages{node=\"1\"} 2\n"));
+        assert!(text.contains("consensus_rounds_per_decision_bucket{node=\"1\",le=\"3\"} 0\n"));
+        assert!(text.contains("consensus_rounds_per_decision_bucket{node=\"1\",le=\"6\"} 1\n"));
+        assert!(text.contains("consensus_rounds_per_decision_bucket{node=\"1\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("consensus_phases_per_decision_sum{node=\"1\"} 2\n"));
+        assert!(text.contains("# TYPE consensus_coin_flips_total counter\n"));
+    }
+
+    #[test]
+    fn nodes_report_to_their_sink() {
+        let registry = Arc::new(Registry::new(4));
+        let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(4));
+        let mut nodes: Vec<_> = (0..4)
+            .map(|id| ConsensusNode::new(id, true, quorum.clone()).with_metrics(registry.clone()))
+            .collect();
+        let mut in_flight: VecDeque<_> = nodes
+            .iter()
+            .flat_map(|node| node.start().messages)
+            .collect();
+        while let Some(message) = in_flight.pop_front() {
+            for node in &mut nodes {
+                in_flight.extend(
+                    node.handle_message(message.sender_id, message.clone())
+                        .messages,
+                );
+            }
+        }
+
+        let text = registry.render();
+        for node in 0..4 {
+            assert!(text.contains(&format!(
+                "consensus_messages_received_total{{node=\"{node}\",type=\"initiate\"}}"
+            )));
+            assert!(text.contains(&format!(
+                "consensus_messages_sent_total{{node=\"{node}\",type=\"decide\"}} 1\n"
+            )));
+            assert!(text.contains(&format!(
+                "consensus_phases_per_decision_count{{node=\"{node}\"}} 1\n"
+            )));
+        }
+        assert!(!text.contains("consensus_coin_flips_total{"), "{text}");
+    }
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn server_exposes_the_registry() {
+        let registry = Arc::new(Registry::new(4));
+        registry.record(2, MetricEvent::CoinFlip);
+        let server = serve(registry, "127.0.0.1:0").unwrap();
+
+        let response = get(server.local_addr(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\n# HELP consensus_messages_sent_total "));
+        assert!(response.contains("\nconsensus_coin_flips_total{node=\"2\"} 1\n"));
+        assert!(get(server.local_addr(), "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        server.shutdown();
+    }
+}
@@ -0,0 +1,730 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    fmt,
+    rc::Rc,
+};
+
+use crate::{
+    broadcast::BroadcastValue,
+    messaging::{Message, MessageType},
+    phase::{ConsensusState, LOOKAHEAD_ROUNDS},
+    quorum::ThresholdQuorum,
+};
+
+/// Values a Byzantine process may put in any message.
+const BYZANTINE_VALUES: [(bool, bool); 4] =
+    [(false, false), (true, false), (false, true), (true, true)];
+
+/// How the faulty processes behave in an exploration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByzantineModel {
+    /// Faulty processes never send anything.
+    Silent,
+    /// Faulty processes may send any message with any value to anyone at any time.
+    Arbitrary,
+}
+
+/// What a single step of an exploration delivers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Granularity {
+    /// The value of a whole reliable broadcast instance. Since every instance is consistent and
+    /// total, as checked in `broadcast`, this explores every outcome of the rounds with far fewer
+    /// states.
+    Broadcast,
+    /// Individual initiate, echo and ready messages. Only practical for very small bounds.
+    Message,
+}
+
+/// Configuration explored by [`check`]. The first `initial_values.len()` processes are honest
+/// and start with `initial_values`, the remaining ones are faulty.
+#[derive(Clone, Debug)]
+pub struct ModelConfig {
+    pub process_count: usize,
+    pub initial_values: Vec<bool>,
+    pub byzantine: ByzantineModel,
+    pub granularity: Granularity,
+    /// Messages of later rounds are never delivered.
+    pub max_round: usize,
+    /// Exploration stops, reporting an incomplete search, after this many distinct states.
+    pub max_states: usize,
+    /// Whether every honest process must have terminated once nothing more can happen, rather
+    /// than all of them or none. Only holds when the bounds leave room for a decision.
+    pub expect_termination: bool,
+}
+
+impl ModelConfig {
+    pub fn new(
+        process_count: usize,
+        initial_values: Vec<bool>,
+        byzantine: ByzantineModel,
+        max_round: usize,
+    ) -> Self {
+        assert!(
+            initial_values.len() <= process_count,
+            "Expected at most one initial value per process"
+        );
+        assert!(
+            max_round <= LOOKAHEAD_ROUNDS,
+            "Expected every explored round to be buffered"
+        );
+        ModelConfig {
+            process_count,
+            initial_values,
+            byzantine,
+            granularity: Granularity::Broadcast,
+            max_round,
+            max_states: 200_000,
+            expect_termination: false,
+        }
+    }
+}
+
+/// Outcome of an exploration that found no violation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub states: usize,
+    pub transitions: usize,
+    /// Whether every reachable state was explored, rather than stopping at `max_states`.
+    pub complete: bool,
+}
+
+/// One step of an exploration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Messages delivered to an honest process, in order.
+    Deliver {
+        to: usize,
+        messages: Vec<Message<bool>>,
+    },
+    /// Value delivered by the broadcast of `source` in `round` to an honest process.
+    Broadcast {
+        to: usize,
+        round: usize,
+        source: usize,
+        value: BroadcastValue<bool>,
+    },
+    /// Value a faulty process makes every honest one deliver from its broadcast in `round`.
+    Choose {
+        source: usize,
+        round: usize,
+        value: BroadcastValue<bool>,
+    },
+    /// Outcome of the coin flip of an honest process.
+    Coin { process: usize, value: bool },
+}
+
+/// Shortest sequence of steps leading from the initial state to a violated invariant.
+#[derive(Clone, Debug)]
+pub struct Counterexample {
+    pub violation: String,
+    pub trace: Vec<Event>,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} after {} steps:", self.violation, self.trace.len())?;
+        for (index, event) in self.trace.iter().enumerate() {
+            match event {
+                Event::Deliver { to, messages } => {
+                    for message in messages {
+                        writeln!(
+                            f,
+                            "{index:>4}: {} -> {to} {:?} round {} source {} value {:?}",
+                            message.sender_id,
+                            message.message_type,
+                            message.round,
+                            message.broadcast_source_id,
+                            message.value,
+                        )?;
+                    }
+                }
+                Event::Broadcast {
+                    to,
+                    round,
+                    source,
+                    value,
+                } => {
+                    writeln!(
+                        f,
+                        "{index:>4}: {to} delivers round {round} source {source} value {value:?}"
+                    )?;
+                }
+                Event::Choose {
+                    source,
+                    round,
+                    value,
+                } => {
+                    writeln!(
+                        f,
+                        "{index:>4}: {source} broadcasts round {round} value {value:?}"
+                    )?;
+                }
+                Event::Coin { process, value } => {
+                    writeln!(f, "{index:>4}: {process} flips {value}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Honest process in a global state. A terminated process never acts again, so only its
+/// decision is kept: whatever path led there, it is the same state.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Process {
+    Running(ConsensusState<bool>),
+    Terminated(Option<bool>),
+}
+
+impl Process {
+    fn decided(&self) -> Option<bool> {
+        match self {
+            Process::Running(state) => state.decided().copied(),
+            Process::Terminated(decided) => *decided,
+        }
+    }
+
+    fn running(&self) -> Option<&ConsensusState<bool>> {
+        match self {
+            Process::Running(state) => Some(state),
+            Process::Terminated(_) => None,
+        }
+    }
+
+    fn running_mut(&mut self) -> &mut ConsensusState<bool> {
+        match self {
+            Process::Running(state) => state,
+            Process::Terminated(_) => unreachable!("Expected terminated processes not to act"),
+        }
+    }
+}
+
+/// One global state: the honest processes, the messages they sent to everyone, the messages
+/// each of them has received and, at broadcast granularity, the value each faulty broadcast
+/// committed to.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct World {
+    nodes: Vec<Process>,
+    sent: BTreeSet<Message<bool>>,
+    received: Vec<BTreeSet<Message<bool>>>,
+    byzantine_values: BTreeMap<(usize, usize), BroadcastValue<bool>>,
+}
+
+struct Explorer<'a> {
+    config: &'a ModelConfig,
+    quorum: ThresholdQuorum,
+    honest_count: usize,
+}
+
+/// Explores every delivery order of the messages exchanged by the honest processes, together
+/// with every message the faulty ones may send and every coin outcome, up to `max_round`.
+/// Decision announcements, faulty ones included, are delivered at any point whatever their
+/// round. Checks in each state that honest processes agree and, when they all started with the
+/// same value, that they decide that value. Once nothing more can happen, also checks that
+/// either every honest process terminated or none did, see [`ModelConfig::expect_termination`].
+///
+/// The search is breadth-first over whole states, so it is exhaustive within the bounds and a
+/// reported counterexample is the shortest among the explored orders. Four reductions keep the
+/// state space small:
+/// - only the steps of the first process that can take one are explored. This is a persistent
+///   set reduction (Godefroid, 1996), sound here because:
+///   - a step of process `p` only changes the state of `p`, what `p` received, and the set of
+///     messages sent. Since that set only grows, no step disables or changes the effect of a
+///     step of another process, so steps of different processes commute. Faulty broadcasts
+///     choose their value in a separate step that comes before any delivery of it, so that
+///     choice cannot be a hidden dependency between processes either,
+///   - every step adds a received message, a delivered value, a coin outcome or a faulty
+///     choice, and rounds are bounded, so the state graph is finite and acyclic. Selective
+///     search with persistent sets then still reaches every state where nothing can happen,
+///   - both invariants only depend on decisions, which are never undone. Any path through a
+///     violating state extends to a final state violating it too, so a violation reachable at
+///     all is still reached, possibly after more steps. The termination check only looks at
+///     final states, which are all reached,
+/// - a terminated process never acts again, so only its decision is kept, merging the states
+///   it reached by different paths,
+/// - a process only receives messages of rounds it has reached: earlier arrivals would just be
+///   buffered and replayed once it gets there. With `max_round` within [`LOOKAHEAD_ROUNDS`], the
+///   buffer has room for every message of a correct sender, and dropping those of a faulty one
+///   is the same as it not sending them,
+/// - a step delivers the fewest announcements of one value, or at message granularity the
+///   fewest messages of one type, value and broadcast instance, that change what the receiver
+///   does. Messages that change nothing can be delivered later with the same effect, and
+///   identical messages from different senders are interchangeable.
+pub fn check(config: &ModelConfig) -> Result<Report, Counterexample> {
+    let explorer = Explorer {
+        config,
+        quorum: ThresholdQuorum::new(config.process_count),
+        honest_count: config.initial_values.len(),
+    };
+    explorer.run()
+}
+
+impl Explorer<'_> {
+    fn run(&self) -> Result<Report, Counterexample> {
+        let initial = Rc::new(self.initial_world());
+        // Whole states are kept rather than their hashes, so that a collision cannot hide one
+        let mut visited = HashSet::from([initial.clone()]);
+        let mut parents = vec![None];
+        let mut queue = VecDeque::from([(initial, 0)]);
+        let mut transitions = 0;
+
+        while let Some((world, index)) = queue.pop_front() {
+            let events = self.events(&world);
+            let checked = self.check_invariants(&world).and_then(|()| {
+                if events.is_empty() {
+                    self.check_termination(&world)
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(violation) = checked {
+                return Err(Counterexample {
+                    violation,
+                    trace: trace(&parents, index),
+                });
+            }
+            for event in events {
+                transitions += 1;
+                let next = Rc::new(self.apply(&world, &event));
+                if !visited.insert(next.clone()) {
+                    continue;
+                }
+                if parents.len() >= self.config.max_states {
+                    return Ok(Report {
+                        states: parents.len(),
+                        transitions,
+                        complete: false,
+                    });
+                }
+                queue.push_back((next, parents.len()));
+                parents.push(Some((index, event)));
+            }
+        }
+
+        Ok(Report {
+            states: parents.len(),
+            transitions,
+            complete: true,
+        })
+    }
+
+    fn initial_world(&self) -> World {
+        let nodes: Vec<_> = self
+            .config
+            .initial_values
+            .iter()
+            .enumerate()
+            .map(|(id, value)| ConsensusState::new(id, *value))
+            .collect();
+        let sent = nodes.iter().flat_map(ConsensusState::start).collect();
+        World {
+            nodes: nodes.into_iter().map(Process::Running).collect(),
+            sent,
+            received: vec![BTreeSet::new(); self.honest_count],
+            byzantine_values: BTreeMap::new(),
+        }
+    }
+
+    fn check_invariants(&self, world: &World) -> Result<(), String> {
+        let decided: Vec<_> = world.nodes.iter().map(Process::decided).collect();
+        let values: BTreeSet<_> = decided.iter().flatten().collect();
+        if values.len() > 1 {
+            return Err(format!("agreement violated: decisions {decided:?}"));
+        }
+        let initial: BTreeSet<_> = self.config.initial_values.iter().collect();
+        if initial.len() == 1 && values.iter().any(|value| !initial.contains(value)) {
+            return Err(format!(
+                "validity violated: started with {initial:?}, decisions {decided:?}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks, once nothing more can happen, that honest processes terminated all together or
+    /// not at all: a quorum of announcements received by one holds a blocking set of honest
+    /// ones, which reach every other honest process and make it decide and announce in turn.
+    fn check_termination(&self, world: &World) -> Result<(), String> {
+        let terminated: Vec<_> = world
+            .nodes
+            .iter()
+            .map(|node| matches!(node, Process::Terminated(_)))
+            .collect();
+        let expected = self.config.expect_termination;
+        if terminated.contains(&false) && (expected || terminated.contains(&true)) {
+            return Err(format!("termination violated: terminated {terminated:?}"));
+        }
+        Ok(())
+    }
+
+    /// Events to explore from `world`: the value of one faulty broadcast if some honest process
+    /// could deliver it and it is not chosen yet, otherwise every step of a single process.
+    fn events(&self, world: &World) -> Vec<Event> {
+        if let Some(events) = self.byzantine_choices(world) {
+            return events;
+        }
+        world
+            .nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(id, node)| Some(self.process_events(world, id, node.running()?)))
+            .find(|events| !events.is_empty())
+            .unwrap_or_default()
+    }
+
+    fn byzantine_choices(&self, world: &World) -> Option<Vec<Event>> {
+        if self.config.granularity != Granularity::Broadcast
+            || self.config.byzantine != ByzantineModel::Arbitrary
+        {
+            return None;
+        }
+        let reached = world
+            .nodes
+            .iter()
+            .filter_map(Process::running)
+            .map(ConsensusState::round)
+            .max()?
+            .min(self.config.max_round);
+        let (round, source) = (0..=reached)
+            .flat_map(|round| {
+                (self.honest_count..self.config.process_count).map(move |source| (round, source))
+            })
+            .find(|instance| !world.byzantine_values.contains_key(instance))?;
+        Some(
+            BYZANTINE_VALUES
+                .iter()
+                .map(|(value, decided)| Event::Choose {
+                    source,
+                    round,
+                    value: BroadcastValue::new(*value, *decided),
+                })
+                .collect(),
+        )
+    }
+
+    fn process_events(&self, world: &World, id: usize, node: &ConsensusState<bool>) -> Vec<Event> {
+        let mut events = self.announcements(world, id);
+        if node.awaiting_coin() {
+            events.extend([false, true].map(|value| Event::Coin { process: id, value }));
+            return events;
+        }
+        for round in 0..=node.round().min(self.config.max_round) {
+            if self.config.granularity == Granularity::Broadcast {
+                events.extend(self.broadcast_deliveries(world, id, round));
+                continue;
+            }
+            for source in 0..self.config.process_count {
+                for message_type in [MessageType::Initiate, MessageType::Echo, MessageType::Ready] {
+                    for (value, decided) in BYZANTINE_VALUES {
+                        let class = (
+                            round,
+                            source,
+                            message_type,
+                            BroadcastValue::new(value, decided),
+                        );
+                        events.extend(self.smallest_deliveries(world, id, &class));
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    /// Fewest decision announcements of one value that change what process `id` does: those
+    /// of honest processes it has not received yet, and one from each faulty process that has
+    /// not announced anything to it.
+    fn announcements(&self, world: &World, id: usize) -> Vec<Event> {
+        let received = &world.received[id];
+        let mut events = Vec::new();
+        for value in [false, true] {
+            let value = BroadcastValue::new(value, true);
+            let honest = world
+                .sent
+                .iter()
+                .filter(|m| {
+                    m.message_type == MessageType::Decide
+                        && m.value == value
+                        && !received.contains(m)
+                })
+                .cloned()
+                .collect();
+            let faulty = match self.config.byzantine {
+                ByzantineModel::Silent => Vec::new(),
+                ByzantineModel::Arbitrary => (self.honest_count..self.config.process_count)
+                    .filter(|sender| {
+                        !received.iter().any(|m| {
+                            m.message_type == MessageType::Decide && m.sender_id == *sender
+                        })
+                    })
+                    .map(|sender| {
+                        Message::new(0, sender, sender, value.clone(), MessageType::Decide)
+                    })
+                    .collect(),
+            };
+            events.extend(self.fewest_changing(world, id, honest, faulty));
+        }
+        events
+    }
+
+    /// Broadcast values of `round` that process `id` has not delivered yet, unless it pruned
+    /// that round.
+    fn broadcast_deliveries(&self, world: &World, id: usize, round: usize) -> Vec<Event> {
+        let Some(state) = world.nodes[id]
+            .running()
+            .and_then(|node| node.rounds().get(&round))
+        else {
+            return Vec::new();
+        };
+        let delivered = state.validated().senders();
+        let mut events = Vec::new();
+        for source in 0..self.config.process_count {
+            if delivered.contains(&source) || state.pending().contains_key(&source) {
+                continue;
+            }
+            let value = if source < self.honest_count {
+                world
+                    .sent
+                    .iter()
+                    .find(|m| m.round == round && m.broadcast_source_id == source)
+                    .map(|m| m.value.clone())
+            } else {
+                world.byzantine_values.get(&(round, source)).cloned()
+            };
+            events.extend(value.map(|value| Event::Broadcast {
+                to: id,
+                round,
+                source,
+                value,
+            }));
+        }
+        events
+    }
+
+    /// Fewest messages of one class that change what process `id` does, for every way of
+    /// splitting them between honest and faulty senders.
+    fn smallest_deliveries(
+        &self,
+        world: &World,
+        id: usize,
+        (round, source, message_type, value): &(usize, usize, MessageType, BroadcastValue<bool>),
+    ) -> Vec<Event> {
+        let matches = |message: &Message<bool>| {
+            message.round == *round
+                && message.broadcast_source_id == *source
+                && message.message_type == *message_type
+                && message.value == *value
+                && !world.received[id].contains(message)
+        };
+        let honest = world.sent.iter().filter(|m| matches(m)).cloned().collect();
+        let faulty = match self.config.byzantine {
+            ByzantineModel::Silent => Vec::new(),
+            ByzantineModel::Arbitrary => (self.honest_count..self.config.process_count)
+                .filter(|sender| *message_type != MessageType::Initiate || sender == source)
+                .map(|sender| Message::new(*round, sender, *source, value.clone(), *message_type))
+                .filter(|m| matches(m))
+                .collect(),
+        };
+        self.fewest_changing(world, id, honest, faulty)
+    }
+
+    /// Fewest of the `honest` and `faulty` messages that change what process `id` does, taken
+    /// in order, for every way of splitting them between both.
+    fn fewest_changing(
+        &self,
+        world: &World,
+        id: usize,
+        honest: Vec<Message<bool>>,
+        faulty: Vec<Message<bool>>,
+    ) -> Vec<Event> {
+        for count in 1..=honest.len() + faulty.len() {
+            let events: Vec<_> = (0..=count.min(honest.len()))
+                .filter(|taken| count - taken <= faulty.len())
+                .map(|taken| Event::Deliver {
+                    to: id,
+                    messages: honest[..taken]
+                        .iter()
+                        .chain(&faulty[..count - taken])
+                        .cloned()
+                        .collect(),
+                })
+                .filter(|event| self.changes_behaviour(world, id, event))
+                .collect();
+            if !events.is_empty() {
+                return events;
+            }
+        }
+        Vec::new()
+    }
+
+    fn changes_behaviour(&self, world: &World, id: usize, event: &Event) -> bool {
+        let Some(before) = world.nodes[id].running() else {
+            return false;
+        };
+        let mut after = before.clone();
+        let mut outgoing = Vec::new();
+        if let Event::Deliver { messages, .. } = event {
+            for message in messages {
+                outgoing.extend(after.handle_message(&self.quorum, message.clone()));
+            }
+        }
+        !outgoing.is_empty() || control_state(before) != control_state(&after)
+    }
+
+    fn apply(&self, world: &World, event: &Event) -> World {
+        let mut next = world.clone();
+        match event {
+            Event::Deliver { to, messages } => {
+                for message in messages {
+                    next.received[*to].insert(message.clone());
+                    let outgoing = next.nodes[*to]
+                        .running_mut()
+                        .handle_message(&self.quorum, message.clone());
+                    next.sent.extend(outgoing);
+                }
+            }
+            Event::Broadcast {
+                to,
+                round,
+                source,
+                value,
+            } => {
+                // A quorum of readies makes the instance deliver, whatever came before
+                for sender in 0..self.config.process_count {
+                    let ready =
+                        Message::new(*round, sender, *source, value.clone(), MessageType::Ready);
+                    let outgoing = next.nodes[*to]
+                        .running_mut()
+                        .handle_message(&self.quorum, ready);
+                    next.sent.extend(outgoing.into_iter().filter(|m| {
+                        matches!(m.message_type, MessageType::Initiate | MessageType::Decide)
+                    }));
+                }
+            }
+            Event::Choose {
+                source,
+                round,
+                value,
+            } => {
+                next.byzantine_values
+                    .insert((*round, *source), value.clone());
+            }
+            Event::Coin { process, value } => {
+                let outgoing = next.nodes[*process]
+                    .running_mut()
+                    .provide_coin(&self.quorum, *value);
+                next.sent.extend(outgoing);
+            }
+        }
+        for (node, received) in next.nodes.iter_mut().zip(&mut next.received) {
+            if let Process::Running(state) = node {
+                if state.terminated() {
+                    *node = Process::Terminated(state.decided().copied());
+                    received.clear();
+                }
+            }
+        }
+        next
+    }
+}
+
+/// Everything about a process except who exactly sent the echoes and readies it counted.
+fn control_state(node: &ConsensusState<bool>) -> impl PartialEq + '_ {
+    let rounds: Vec<_> = node
+        .rounds()
+        .values()
+        .map(|round| (round.validated(), round.pending()))
+        .collect();
+    (
+        node.round(),
+        node.value().clone(),
+        node.decided().copied(),
+        node.terminated(),
+        node.awaiting_coin(),
+        rounds,
+    )
+}
+
+fn trace(parents: &[Option<(usize, Event)>], mut index: usize) -> Vec<Event> {
+    let mut trace = Vec::new();
+    while let Some((parent, event)) = &parents[index] {
+        trace.push(event.clone());
+        index = *parent;
+    }
+    trace.reverse();
+    trace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every assignment of initial values to `honest_count` processes, up to symmetry.
+    fn initial_values(honest_count: usize) -> Vec<Vec<bool>> {
+        (0..=honest_count)
+            .map(|ones| (0..honest_count).map(|id| id < ones).collect())
+            .collect()
+    }
+
+    fn assert_safe(config: &ModelConfig) {
+        match check(config) {
+            Ok(report) => assert!(report.complete, "Expected {config:?} to be fully explored"),
+            Err(counterexample) => panic!("{config:?}\n{counterexample}"),
+        }
+    }
+
+    #[test]
+    fn silent_faulty_process_cannot_break_agreement() {
+        for values in initial_values(3) {
+            assert_safe(&ModelConfig::new(4, values, ByzantineModel::Silent, 5));
+        }
+    }
+
+    #[test]
+    fn arbitrary_faulty_process_cannot_break_agreement() {
+        for values in initial_values(3) {
+            assert_safe(&ModelConfig::new(4, values, ByzantineModel::Arbitrary, 1));
+        }
+    }
+
+    /// With the same initial value everywhere, the first phase has every honest process decide
+    /// it whatever the faulty one sends. Checking that they all terminate within its two rounds
+    /// covers any bound: decisions are never undone, so later rounds cannot break validity.
+    #[test]
+    fn arbitrary_faulty_process_cannot_break_validity() {
+        for value in [false, true] {
+            let mut config = ModelConfig::new(4, vec![value; 3], ByzantineModel::Arbitrary, 2);
+            config.expect_termination = true;
+            assert_safe(&config);
+        }
+    }
+
+    #[test]
+    fn message_granularity_agrees_with_broadcast_granularity() {
+        for values in initial_values(3) {
+            let mut config = ModelConfig::new(4, values, ByzantineModel::Silent, 0);
+            config.granularity = Granularity::Message;
+            assert_safe(&config);
+        }
+    }
+
+    #[test]
+    fn too_many_faulty_processes_yield_a_shortest_counterexample() {
+        let config = ModelConfig::new(4, vec![true, true], ByzantineModel::Arbitrary, 2);
+        let counterexample = check(&config)
+            .expect_err("Expected two faulty processes out of four to break validity");
+        assert!(counterexample.violation.starts_with("validity violated"));
+
+        // Replaying the trace violates validity only after its last step
+        let explorer = Explorer {
+            config: &config,
+            quorum: ThresholdQuorum::new(config.process_count),
+            honest_count: config.initial_values.len(),
+        };
+        let mut world = explorer.initial_world();
+        for event in &counterexample.trace {
+            assert!(explorer.check_invariants(&world).is_ok());
+            assert!(explorer.events(&world).contains(event));
+            world = explorer.apply(&world, event);
+        }
+        assert!(explorer.check_invariants(&world).is_err());
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crossbeam::channel::{self, Receiver, Select, Sender, TryRecvError, TrySendError};
+
+use crate::{messaging::Message, phase::LOOKAHEAD_ROUNDS, util::CancellationToken};
+
+/// How long an async sender waits by default for room in a full queue before dropping its
+/// message.
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Peers are penalized while more than one of their messages was dropped for every
+/// `PENALTY_RATIO` received from them.
+pub const PENALTY_RATIO: usize = 4;
+
+/// Size of the queue each process has for each of its peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelConfig {
+    /// Messages each peer may have queued at a process, without limit if `None`. Each sender
+    /// also keeps as many messages aside for a peer whose queue is full.
+    pub capacity: Option<usize>,
+    /// How long a full queue holds back a sender of the async transports in
+    /// [`transport`](crate::transport) before the message is dropped. Senders of the thread
+    /// runtime never wait, see [`PeerSender::send`].
+    pub send_timeout: Duration,
+}
+
+impl ChannelConfig {
+    /// Room for everything a correct peer sends while up to [`LOOKAHEAD_ROUNDS`] ahead: an
+    /// initiate, an echo and a ready per source and a decision announcement each round.
+    pub fn for_cluster(process_count: usize) -> ChannelConfig {
+        ChannelConfig::bounded(LOOKAHEAD_ROUNDS * (2 * process_count + 2))
+    }
+
+    /// # Panics
+    ///
+    /// If `capacity` is 0: a queue without room would set every message aside, where nothing
+    /// wakes the receiving process up.
+    pub fn bounded(capacity: usize) -> ChannelConfig {
+        assert!(
+            capacity > 0,
+            "Expected room for at least one message per peer"
+        );
+        ChannelConfig {
+            capacity: Some(capacity),
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+        }
+    }
+
+    pub fn unbounded() -> ChannelConfig {
+        ChannelConfig {
+            capacity: None,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+        }
+    }
+}
+
+/// Traffic from one peer, as seen by the process receiving it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Messages waiting in the queue or set aside by the sender.
+    pub queued: usize,
+    pub received: usize,
+    /// Messages dropped because both the queue and the room set aside were full.
+    pub dropped: usize,
+}
+
+/// State of the link from one process to one peer, shared by both ends.
+#[derive(Debug)]
+struct Shared<T> {
+    received: AtomicUsize,
+    dropped: AtomicUsize,
+    /// Messages the sender set aside while the queue was full, received once the queue is
+    /// empty so that the link stays first in, first out.
+    overflow: Mutex<VecDeque<Message<T>>>,
+    overflow_capacity: usize,
+}
+
+impl<T> Shared<T> {
+    fn new(overflow_capacity: usize) -> Shared<T> {
+        Shared {
+            received: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            overflow: Mutex::new(VecDeque::new()),
+            overflow_capacity,
+        }
+    }
+
+    fn stats(&self, queue_len: usize) -> PeerStats {
+        PeerStats {
+            queued: queue_len + self.overflow.lock().unwrap().len(),
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What [`Inbox::recv_or`] received.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received<T, R> {
+    /// A message and the id of the peer whose queue it came from.
+    Message(usize, Message<T>),
+    Other(R),
+}
+
+/// Sending end of the queue a process keeps for one peer.
+#[derive(Debug)]
+pub struct PeerSender<T> {
+    queue: Sender<Message<T>>,
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for PeerSender<T> {
+    fn clone(&self) -> Self {
+        PeerSender {
+            queue: self.queue.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> PeerSender<T> {
+    /// Queues `message` without waiting, so that a peer that stops reading cannot hold back
+    /// messages to the others. Messages that find the queue full are set aside until the peer
+    /// catches up, and only dropped once as many are waiting there. Returns whether the message
+    /// was queued or set aside.
+    pub fn send(&self, message: Message<T>) -> bool {
+        let mut overflow = self.shared.overflow.lock().unwrap();
+        // Set aside messages go first
+        let message = if overflow.is_empty() {
+            match self.queue.try_send(message) {
+                Ok(()) => return true,
+                Err(TrySendError::Full(message)) => message,
+                // The process has stopped
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        } else {
+            message
+        };
+        if overflow.len() >= self.shared.overflow_capacity {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        overflow.push_back(message);
+        true
+    }
+
+    /// Traffic through the queue so far, as [`Inbox::stats`] reports it to the receiving process.
+    pub fn stats(&self) -> PeerStats {
+        self.shared.stats(self.queue.len())
+    }
+}
+
+/// Receiving end of the queues a process keeps for its peers.
+///
+/// Peers are served in turn, so a flooding peer only gets its share of the process' time. Peers
+/// that recently had messages dropped for sending more than their queue and the room set aside
+/// could hold are penalized: they are only served when no other peer has anything queued. The
+/// penalty is relative to what they send, see [`PENALTY_RATIO`], so it lapses once their traffic
+/// gets through again.
+#[derive(Debug)]
+pub struct Inbox<T> {
+    queues: Vec<Receiver<Message<T>>>,
+    shared: Vec<Arc<Shared<T>>>,
+    open: Vec<bool>,
+    next: usize,
+}
+
+impl<T> Inbox<T> {
+    /// Next queued message, if any, along with the id of the peer whose queue it came from.
+    pub fn try_recv(&mut self) -> Option<(usize, Message<T>)> {
+        let count = self.queues.len();
+        for penalized in [false, true] {
+            for offset in 0..count {
+                let peer = (self.next + offset) % count;
+                if !self.open[peer] || self.is_penalized(peer) != penalized {
+                    continue;
+                }
+                if let Some(message) = self.try_recv_from(peer) {
+                    self.shared[peer].received.fetch_add(1, Ordering::Relaxed);
+                    self.next = peer + 1;
+                    return Some((peer, message));
+                }
+            }
+        }
+        None
+    }
+
+    fn try_recv_from(&mut self, peer: usize) -> Option<Message<T>> {
+        let disconnected = match self.queues[peer].try_recv() {
+            Ok(message) => return Some(message),
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => true,
+        };
+        let message = self.shared[peer].overflow.lock().unwrap().pop_front();
+        if message.is_none() && disconnected {
+            self.open[peer] = false;
+        }
+        message
+    }
+
+    /// Waits for the next message and the id of the peer whose queue it came from, or returns
+    /// `None` once cancelled or once every peer is gone. That id is the only sender a message
+    /// can be trusted to come from, whatever sender it names.
+    pub fn recv(&mut self, cancel: &CancellationToken) -> Option<(usize, Message<T>)> {
+        match self.recv_or(cancel, &channel::never::<Infallible>())? {
+            Received::Message(from, message) => Some((from, message)),
+            Received::Other(never) => match never {},
+        }
+    }
+
+    /// Waits for the next message or for something to receive from `other`, such as a local
+    /// request. Returns `None` once cancelled or once every peer is gone, whether `other` is
+    /// still open or not.
+    pub fn recv_or<R>(
+        &mut self,
+        cancel: &CancellationToken,
+        other: &Receiver<R>,
+    ) -> Option<Received<T, R>> {
+        let mut other_open = true;
+        loop {
+            if cancel.is_cancelled() {
+                return None;
+            }
+            if let Some((from, message)) = self.try_recv() {
+                return Some(Received::Message(from, message));
+            }
+            if other_open {
+                match other.try_recv() {
+                    Ok(value) => return Some(Received::Other(value)),
+                    Err(TryRecvError::Disconnected) => other_open = false,
+                    Err(TryRecvError::Empty) => (),
+                }
+            }
+            if !self.open.contains(&true) {
+                return None;
+            }
+
+            let mut select = Select::new();
+            for (queue, _) in self
+                .queues
+                .iter()
+                .zip(&self.open)
+                .filter(|(_, open)| **open)
+            {
+                select.recv(queue);
+            }
+            if other_open {
+                select.recv(other);
+            }
+            select.recv(cancel.receiver());
+            select.ready();
+        }
+    }
+
+    /// Traffic from each peer, by id.
+    pub fn stats(&self) -> Vec<PeerStats> {
+        self.queues
+            .iter()
+            .zip(&self.shared)
+            .map(|(queue, shared)| shared.stats(queue.len()))
+            .collect()
+    }
+
+    fn is_penalized(&self, peer: usize) -> bool {
+        let shared = &self.shared[peer];
+        let dropped = shared.dropped.load(Ordering::Relaxed);
+        dropped > 0 && dropped * PENALTY_RATIO > shared.received.load(Ordering::Relaxed)
+    }
+}
+
+/// Connects `process_count` processes to each other, returning for each process, by id, the
+/// senders to every process and its own inbox.
+///
+/// # Panics
+///
+/// If `config` leaves no room in the queues, see [`ChannelConfig::bounded`].
+pub fn connect<T>(
+    process_count: usize,
+    config: ChannelConfig,
+) -> Vec<(Vec<PeerSender<T>>, Inbox<T>)> {
+    assert_ne!(
+        config.capacity,
+        Some(0),
+        "Expected room for at least one message per peer"
+    );
+    let mut senders: Vec<Vec<_>> = (0..process_count).map(|_| Vec::new()).collect();
+    let mut inboxes = Vec::with_capacity(process_count);
+    for _ in 0..process_count {
+        let mut queues = Vec::with_capacity(process_count);
+        let mut shared_states = Vec::with_capacity(process_count);
+        for sender in &mut senders {
+            let (queue_sender, queue) = match config.capacity {
+                Some(capacity) => channel::bounded(capacity),
+                None => channel::unbounded(),
+            };
+            // Unbounded queues are never full, so nothing is ever set aside
+            let shared = Arc::new(Shared::new(config.capacity.unwrap_or(0)));
+            sender.push(PeerSender {
+                queue: queue_sender,
+                shared: shared.clone(),
+            });
+            queues.push(queue);
+            shared_states.push(shared);
+        }
+        inboxes.push(Inbox {
+            queues,
+            shared: shared_states,
+            open: vec![true; process_count],
+            next: 0,
+        });
+    }
+    senders.into_iter().zip(inboxes).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{broadcast::BroadcastValue, messaging::MessageType};
+
+    fn message(sender: usize) -> Message<bool> {
+        let value = BroadcastValue::new(true, false);
+        Message::new(0, sender, sender, value, MessageType::Initiate)
+    }
+
+    #[test]
+    fn peers_are_served_in_turn() {
+        let mut endpoints = connect(3, ChannelConfig::unbounded());
+        for _ in 0..3 {
+            endpoints[0].0[2].send(message(0));
+        }
+        endpoints[1].0[2].send(message(1));
+        endpoints[2].0[2].send(message(2));
+
+        let inbox = &mut endpoints[2].1;
+        let order: Vec<_> = std::iter::from_fn(|| inbox.try_recv())
+            .map(|(from, _)| from)
+            .collect();
+        assert_eq!(order, vec![0, 1, 2, 0, 0]);
+    }
+
+    #[test]
+    fn full_queues_set_messages_aside_and_penalties_lapse() {
+        let mut endpoints = connect(2, ChannelConfig::bounded(2));
+        let sent: Vec<_> = (0..5)
+            .map(|round| {
+                let value = BroadcastValue::new(true, false);
+                endpoints[0].0[1].send(Message::new(round, 0, 0, value, MessageType::Echo))
+            })
+            .collect();
+        assert_eq!(sent, vec![true, true, true, true, false]);
+        endpoints[1].0[1].send(message(1));
+
+        // The overflowing peer is served last while penalized, in the order it sent
+        let inbox = &mut endpoints[1].1;
+        let order: Vec<_> = std::iter::from_fn(|| inbox.try_recv())
+            .map(|(from, message)| (from, message.round))
+            .collect();
+        assert_eq!(order, vec![(1, 0), (0, 0), (0, 1), (0, 2), (0, 3)]);
+        assert_eq!(
+            inbox.stats()[0],
+            PeerStats {
+                queued: 0,
+                received: 4,
+                dropped: 1,
+            }
+        );
+        assert!(!inbox.is_penalized(0));
+    }
+
+    #[test]
+    fn recv_or_waits_for_either_side() {
+        let mut endpoints = connect(2, ChannelConfig::unbounded());
+        let (requests, other) = channel::unbounded();
+        let cancel = CancellationToken::new();
+        requests.send("request").unwrap();
+        endpoints[0].0[1].send(message(0));
+
+        let inbox = &mut endpoints[1].1;
+        assert_eq!(
+            inbox.recv_or(&cancel, &other),
+            Some(Received::Message(0, message(0)))
+        );
+        assert_eq!(
+            inbox.recv_or(&cancel, &other),
+            Some(Received::Other("request"))
+        );
+        drop(requests);
+        cancel.cancel();
+        assert_eq!(inbox.recv_or(&cancel, &other), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected room for at least one message per peer")]
+    fn queues_need_room() {
+        ChannelConfig::bounded(0);
+    }
+
+    #[test]
+    fn single_message_queues_wake_the_receiver_up() {
+        let mut endpoints = connect(2, ChannelConfig::bounded(1));
+        let (senders, _) = endpoints.remove(0);
+        let (_, mut inbox) = endpoints.remove(0);
+        let (received, receipts) = channel::unbounded();
+        let receiver = std::thread::spawn(move || {
+            let cancel = CancellationToken::new();
+            for _ in 0..5 {
+                received.send(inbox.recv(&cancel)).unwrap();
+            }
+        });
+
+        // Each message only goes once the previous one got through, while the receiver waits
+        for round in 0..5 {
+            let value = BroadcastValue::new(true, false);
+            let message = Message::new(round, 0, 0, value, MessageType::Initiate);
+            assert!(senders[1].send(message.clone()));
+            assert_eq!(receipts.recv().unwrap(), Some((0, message)));
+        }
+        receiver.join().unwrap();
+    }
+}
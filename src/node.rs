@@ -0,0 +1,295 @@
+use std::sync::Arc;
+
+use crate::{
+    messaging::Message,
+    metrics::{MetricEvent, MetricsSink},
+    phase::ConsensusState,
+    quorum::QuorumSystem,
+    util::Broadcastable,
+};
+
+/// What a [`ConsensusNode`] asks of its runtime after an input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Step<T> {
+    /// Messages to send to every process, the local one included.
+    pub messages: Vec<Message<T>>,
+    /// Whether a coin flip has to be passed to [`ConsensusNode::provide_coin`] before the node
+    /// can go on.
+    pub coin_requested: bool,
+    /// Value decided by the local process, in the one step that decided it.
+    pub decided: Option<T>,
+    /// Whether the node has nothing left to do and can be dropped.
+    pub terminated: bool,
+}
+
+impl<T> Default for Step<T> {
+    fn default() -> Self {
+        Step {
+            messages: Vec::new(),
+            coin_requested: false,
+            decided: None,
+            terminated: false,
+        }
+    }
+}
+
+/// One process of the consensus protocol, free of any IO so that it can be driven by threads,
+/// tasks or a simulation alike: the runtime feeds it what it receives and carries out the
+/// returned [`Step`]s.
+#[derive(Clone, Debug)]
+pub struct ConsensusNode<T> {
+    state: ConsensusState<T>,
+    quorum: Arc<dyn QuorumSystem>,
+    reported: bool,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    /// Rejected values already reported to `metrics`.
+    reported_rejections: usize,
+}
+
+impl<T> ConsensusNode<T>
+where
+    T: Broadcastable,
+{
+    pub fn new(id: usize, initial_value: T, quorum: Arc<dyn QuorumSystem>) -> ConsensusNode<T> {
+        ConsensusNode {
+            state: ConsensusState::new(id, initial_value),
+            quorum,
+            reported: false,
+            metrics: None,
+            reported_rejections: 0,
+        }
+    }
+
+    /// Reports the node's traffic, buffer and decision to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> ConsensusNode<T> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn id(&self) -> usize {
+        self.state.id()
+    }
+
+    pub fn state(&self) -> &ConsensusState<T> {
+        &self.state
+    }
+
+    pub fn decided(&self) -> Option<&T> {
+        self.state.decided()
+    }
+
+    pub fn terminated(&self) -> bool {
+        self.state.terminated()
+    }
+
+    /// Messages starting the protocol.
+    pub fn start(&self) -> Step<T> {
+        let _span = self.span().entered();
+        Step {
+            messages: self.state.start(),
+            ..Step::default()
+        }
+    }
+
+    /// Processes `message`, received from process `from` as authenticated by the runtime.
+    /// Messages claiming to come from another process are ignored.
+    pub fn handle_message(&mut self, from: usize, message: Message<T>) -> Step<T> {
+        if message.sender_id != from || self.terminated() {
+            return Step::default();
+        }
+        let _span = self.span().entered();
+        self.record(MetricEvent::Received(message.message_type));
+        let messages = self.state.handle_message(self.quorum.as_ref(), message);
+        self.step(messages)
+    }
+
+    /// Goes on with the outcome of the coin flip requested by the last step.
+    pub fn provide_coin(&mut self, value: T) -> Step<T> {
+        let _span = self.span().entered();
+        tracing::debug!(?value, "coin flipped");
+        self.record(MetricEvent::CoinFlip);
+        let messages = self.state.provide_coin(self.quorum.as_ref(), value);
+        self.step(messages)
+    }
+
+    /// Span around everything the node logs, so that logs can be filtered by process.
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("node", id = self.id())
+    }
+
+    fn record(&self, event: MetricEvent) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record(self.id(), event);
+        }
+    }
+
+    fn step(&mut self, messages: Vec<Message<T>>) -> Step<T> {
+        let decided = match self.state.decided() {
+            Some(value) if !self.reported => {
+                self.reported = true;
+                Some(value.clone())
+            }
+            _ => None,
+        };
+        if self.metrics.is_some() {
+            for message in &messages {
+                self.record(MetricEvent::Sent(message.message_type));
+            }
+            if decided.is_some() {
+                let (round, phase) = (self.state.round(), self.state.phase());
+                self.record(MetricEvent::Decided { round, phase });
+            }
+            let buffers = self.state.metrics();
+            let rejected = buffers.rejected_values - self.reported_rejections;
+            if rejected > 0 {
+                self.record(MetricEvent::Rejected(rejected));
+                self.reported_rejections = buffers.rejected_values;
+            }
+            self.record(MetricEvent::EarlyMessages(buffers.early_messages));
+        }
+        Step {
+            messages,
+            coin_requested: self.state.awaiting_coin(),
+            decided,
+            terminated: self.state.terminated(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{
+        broadcast::BroadcastValue,
+        messaging::MessageType,
+        network::{self, ChannelConfig},
+        quorum::ThresholdQuorum,
+    };
+
+    fn cluster(initial_values: &[bool]) -> Vec<ConsensusNode<bool>> {
+        let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(initial_values.len()));
+        initial_values
+            .iter()
+            .enumerate()
+            .map(|(id, value)| ConsensusNode::new(id, *value, quorum.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn unanimous_nodes_decide_once_and_terminate() {
+        let mut nodes = cluster(&[true; 4]);
+        let mut in_flight: VecDeque<_> = nodes
+            .iter()
+            .flat_map(|node| node.start().messages)
+            .collect();
+        let mut decisions = vec![Vec::new(); nodes.len()];
+
+        while let Some(message) = in_flight.pop_front() {
+            for node in &mut nodes {
+                let step = node.handle_message(message.sender_id, message.clone());
+                assert!(!step.coin_requested, "Expected no coin flip when unanimous");
+                decisions[node.id()].extend(step.decided);
+                in_flight.extend(step.messages);
+            }
+        }
+
+        assert!(nodes.iter().all(ConsensusNode::terminated));
+        assert!(
+            decisions.iter().all(|values| values == &[true]),
+            "{decisions:?}"
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_nest_rounds_and_broadcasts_in_their_node() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter("async_byz_consensus[node{id=1}]=debug")
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut nodes = cluster(&[true; 4]);
+            let mut in_flight: VecDeque<_> = nodes
+                .iter()
+                .flat_map(|node| node.start().messages)
+                .collect();
+            while let Some(message) = in_flight.pop_front() {
+                for node in &mut nodes {
+                    in_flight.extend(
+                        node.handle_message(message.sender_id, message.clone())
+                            .messages,
+                    );
+                }
+            }
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("node{id=1}:phase{phase=0}:round{round=0}:broadcast{source=2}:"),
+            "{logs}"
+        );
+        for event in [
+            "echo quorum reached",
+            "ready sent",
+            "value delivered",
+            "value validated",
+        ] {
+            assert!(logs.contains(event), "Expected {event} in {logs}");
+        }
+        assert!(logs.contains("decided value=true") && logs.contains("terminated"));
+        assert!(!logs.contains("node{id=0}"));
+    }
+
+    #[test]
+    fn spoofed_messages_are_ignored() {
+        let mut node = cluster(&[true; 4]).remove(0);
+        let value = BroadcastValue::new(false, false);
+        let spoofed = Message::new(0, 1, 1, value, MessageType::Initiate);
+
+        let before = node.state().clone();
+        assert_eq!(node.handle_message(2, spoofed), Step::default());
+        assert_eq!(node.state(), &before);
+    }
+
+    #[test]
+    fn spoofed_messages_are_ignored_over_the_network() {
+        let mut endpoints = network::connect(4, ChannelConfig::for_cluster(4));
+        let initiate = Message::new(
+            0,
+            3,
+            3,
+            BroadcastValue::new(false, false),
+            MessageType::Initiate,
+        );
+        // Process 2 initiates a broadcast in the name of process 3, before process 3 does
+        endpoints[2].0[0].send(initiate.clone());
+        endpoints[3].0[0].send(initiate);
+
+        let mut node = cluster(&[true; 4]).remove(0);
+        let inbox = &mut endpoints[0].1;
+        let echoes: Vec<_> = std::iter::from_fn(|| inbox.try_recv())
+            .map(|(from, message)| (from, node.handle_message(from, message).messages.len()))
+            .collect();
+        assert_eq!(echoes, vec![(2, 0), (3, 1)]);
+    }
+}
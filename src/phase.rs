@@ -1,79 +1,433 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
 
-use crossbeam::channel::Receiver;
+use tracing::span::EnteredSpan;
 
 use crate::{
-    broadcast::{BroadcastSender, BroadcastValue},
-    messaging::Message,
-    round, selection_protocol,
-    util::{self, Broadcastable},
-    validation::ValidatedMessageSet,
+    broadcast::BroadcastValue,
+    messaging::{EarlyMessages, Message, MessageType},
+    quorum::QuorumSystem,
+    round::RoundState,
+    selection_protocol,
+    util::Broadcastable,
 };
 
-pub fn phase<T>(
+/// Rounds ahead of the local one whose messages are buffered, later ones are dropped. Processes
+/// that lag further behind a quorum are caught up by its decision announcements instead.
+pub const LOOKAHEAD_ROUNDS: usize = 9;
+
+/// Sizes of what a process keeps in memory, to check that faulty processes cannot grow it, along
+/// with what it turned down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BufferMetrics {
+    /// Messages buffered for rounds the local process has not reached yet.
+    pub early_messages: usize,
+    /// Messages dropped for being too far ahead or over their sender's share of the buffer.
+    pub dropped_messages: usize,
+    /// Rounds whose state is kept.
+    pub rounds: usize,
+    /// Rounds whose state was discarded once they could no longer matter.
+    pub pruned_rounds: usize,
+    /// Delivered values that failed their first validation attempt, see [`RoundState::rejected`].
+    pub rejected_values: usize,
+}
+
+/// Event-driven state of the consensus protocol at one process.
+///
+/// Every phase is made of three rounds. A round ends once a quorum of values has been validated,
+/// at which point `selection_protocol` picks the value broadcast in the next round. Rounds that
+/// have ended keep running their broadcasts and validating late values, since later rounds are
+/// validated against them and other processes may still depend on our echoes.
+///
+/// A process that decides announces it with a [`MessageType::Decide`] message and keeps taking
+/// part in later phases, so that processes which only adopted the value can decide too. An
+/// announcement from a blocking set is enough to decide and announce as well, and one from a
+/// quorum to terminate: every other correct process is then bound to receive a quorum of them.
+///
+/// Messages for future rounds are only buffered within [`LOOKAHEAD_ROUNDS`], and each sender
+/// only gets as much room as a correct process could use there. Announcements, like echoes and
+/// readies, only count for the first value each sender announces. Once decided, the process keeps
+/// just its current round and the one it is validated against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConsensusState<T> {
     id: usize,
-    process_count: usize,
-    faulty_count: usize,
-    phase_counter: usize,
-    initial_value: BroadcastValue<T>,
-    sender: BroadcastSender<T>,
-    receiver: Receiver<Message<T>>,
-    early_messages: HashMap<usize, Vec<Message<T>>>,
-    random_value: fn() -> T,
-) -> (
-    BroadcastValue<T>,
-    HashMap<usize, Vec<Message<T>>>,
-    Receiver<Message<T>>,
-)
+    round: usize,
+    value: BroadcastValue<T>,
+    rounds: BTreeMap<usize, RoundState<T>>,
+    early_messages: EarlyMessages<T>,
+    early_senders: BTreeMap<usize, usize>,
+    metrics: BufferMetrics,
+    awaiting_coin: bool,
+    decided: Option<T>,
+    announced: bool,
+    decide_senders: BTreeMap<T, BTreeSet<usize>>,
+    terminated: bool,
+}
+
+impl<T> ConsensusState<T>
 where
     T: Broadcastable,
 {
-    let mut round_counter = 3 * phase_counter;
-    let mut current_value = initial_value;
-    //TODO avoid hardcoding
-    let (validated_messages, early_messages, receiver) = round::round(
-        round_counter,
-        id,
-        current_value,
-        process_count,
-        early_messages,
-        ValidatedMessageSet::new(),
-        receiver,
-        sender.clone(),
-    );
-    current_value =
-        selection_protocol::selection_protocol(round_counter, process_count, &validated_messages)
-            .expect("Expected phase stage one to have a majority");
-    round_counter += 1;
-
-    let (validated_messages, early_messages, receiver) = round::round(
-        round_counter,
-        id,
-        current_value.clone(),
-        process_count,
-        early_messages,
-        validated_messages,
-        receiver,
-        sender.clone(),
-    );
-    current_value =
-        selection_protocol::selection_protocol(round_counter, process_count, &validated_messages)
-            .unwrap_or(current_value);
-    round_counter += 1;
-
-    let (validated_messages, early_messages, receiver) = round::round(
-        round_counter,
-        id,
-        current_value,
-        process_count,
-        early_messages,
-        validated_messages,
-        receiver,
-        sender.clone(),
-    );
-    let final_value =
-        selection_protocol::selection_protocol(round_counter, process_count, &validated_messages)
-            .unwrap_or(BroadcastValue::new(random_value(), false));
-
-    (final_value, early_messages, receiver)
+    pub fn new(id: usize, initial_value: T) -> ConsensusState<T> {
+        ConsensusState {
+            id,
+            round: 0,
+            value: BroadcastValue::new(initial_value, false),
+            rounds: BTreeMap::from([(0, RoundState::new(id, 0))]),
+            early_messages: BTreeMap::new(),
+            early_senders: BTreeMap::new(),
+            metrics: BufferMetrics::default(),
+            awaiting_coin: false,
+            decided: None,
+            announced: false,
+            decide_senders: BTreeMap::new(),
+            terminated: false,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn round(&self) -> usize {
+        self.round
+    }
+
+    pub fn phase(&self) -> usize {
+        self.round / 3
+    }
+
+    /// Value the local process broadcasts in its current round.
+    pub fn value(&self) -> &BroadcastValue<T> {
+        &self.value
+    }
+
+    pub fn decided(&self) -> Option<&T> {
+        self.decided.as_ref()
+    }
+
+    /// Whether the phase ended without a value to adopt and a coin flip has to be provided
+    /// through [`ConsensusState::provide_coin`] before the next phase can start.
+    pub fn awaiting_coin(&self) -> bool {
+        self.awaiting_coin
+    }
+
+    /// Whether a quorum announced its decision, so the local process can stop.
+    pub fn terminated(&self) -> bool {
+        self.terminated
+    }
+
+    pub fn metrics(&self) -> BufferMetrics {
+        BufferMetrics {
+            rounds: self.rounds.len(),
+            ..self.metrics
+        }
+    }
+
+    pub fn rounds(&self) -> &BTreeMap<usize, RoundState<T>> {
+        &self.rounds
+    }
+
+    /// Messages to send to every process to start the first round.
+    pub fn start(&self) -> Vec<Message<T>> {
+        vec![self.rounds[&self.round].initiate(self.value.clone())]
+    }
+
+    /// Processes a received message and returns the messages to send to every process.
+    pub fn handle_message(
+        &mut self,
+        quorum: &dyn QuorumSystem,
+        message: Message<T>,
+    ) -> Vec<Message<T>> {
+        if message.message_type == MessageType::Decide {
+            return self.handle_decide(quorum, message);
+        }
+        let round = message.round;
+        if round > self.round {
+            self.buffer(quorum, message);
+            return Vec::new();
+        }
+
+        let spans = enter_round(round);
+        let mut outgoing = self
+            .rounds
+            .get_mut(&round)
+            .map_or_else(Vec::new, |state| state.handle_message(quorum, message));
+        self.validate_from(quorum, round);
+        drop(spans);
+        outgoing.extend(self.progress(quorum));
+        outgoing
+    }
+
+    /// Starts the next phase with the outcome of a coin flip.
+    pub fn provide_coin(&mut self, quorum: &dyn QuorumSystem, value: T) -> Vec<Message<T>> {
+        assert!(self.awaiting_coin, "Expected a coin flip to be pending");
+        self.awaiting_coin = false;
+        self.value = BroadcastValue::new(value, false);
+        let mut outgoing = self.start_next_round(quorum);
+        outgoing.extend(self.progress(quorum));
+        outgoing
+    }
+
+    /// Keeps a message for a later round, unless it is too far ahead or its sender already
+    /// buffered more than a correct process could have sent.
+    fn buffer(&mut self, quorum: &dyn QuorumSystem, message: Message<T>) {
+        // A correct process sends an initiate, and an echo and a ready per source, each round
+        let sender_cap = LOOKAHEAD_ROUNDS * (2 * quorum.process_count() + 1);
+        // Checked before making room for the sender, so that made-up senders take none
+        if message.round > self.round + LOOKAHEAD_ROUNDS
+            || message.sender_id >= quorum.process_count()
+        {
+            self.metrics.dropped_messages += 1;
+            return;
+        }
+        let buffered = self.early_senders.entry(message.sender_id).or_default();
+        if *buffered >= sender_cap {
+            self.metrics.dropped_messages += 1;
+            return;
+        }
+        *buffered += 1;
+        self.metrics.early_messages += 1;
+        self.early_messages
+            .entry(message.round)
+            .or_default()
+            .push(message);
+    }
+
+    fn handle_decide(&mut self, quorum: &dyn QuorumSystem, message: Message<T>) -> Vec<Message<T>> {
+        if message.sender_id >= quorum.process_count() {
+            return Vec::new();
+        }
+        // A correct process announces a single value, later ones would only take up memory
+        if self
+            .decide_senders
+            .values()
+            .any(|senders| senders.contains(&message.sender_id))
+        {
+            return Vec::new();
+        }
+        let value = message.value.value;
+        let senders = self.decide_senders.entry(value.clone()).or_default();
+        senders.insert(message.sender_id);
+        let (blocking, complete) = (quorum.is_blocking(senders), quorum.is_quorum(senders));
+
+        let mut outgoing = Vec::new();
+        if blocking {
+            outgoing.extend(self.decide(value));
+        }
+        if complete && !self.terminated {
+            tracing::info!("terminated");
+            self.terminated = true;
+        }
+        outgoing
+    }
+
+    /// Discards the rounds before the one the current round is validated against.
+    fn prune(&mut self) {
+        let oldest = self.round.saturating_sub(1);
+        let before = self.rounds.len();
+        self.rounds.retain(|round, _| *round >= oldest);
+        self.metrics.pruned_rounds += before - self.rounds.len();
+    }
+
+    /// Records the decision, returning its announcement the first time.
+    fn decide(&mut self, value: T) -> Option<Message<T>> {
+        if self.decided.is_none() {
+            tracing::info!(value = ?value, "decided");
+            self.decided = Some(value.clone());
+        }
+        if self.announced {
+            return None;
+        }
+        self.announced = true;
+        let value = BroadcastValue::new(value, true);
+        Some(Message::new(
+            self.round,
+            self.id,
+            self.id,
+            value,
+            MessageType::Decide,
+        ))
+    }
+
+    /// Validates pending values of `round` and of every later round, whose validation depends on
+    /// it.
+    fn validate_from(&mut self, quorum: &dyn QuorumSystem, round: usize) {
+        for current in round..=self.round {
+            let Some(mut state) = self.rounds.remove(&current) else {
+                continue;
+            };
+            let rejected = state.rejected().len();
+            match current.checked_sub(1) {
+                None => {
+                    state.validate_pending(quorum, None);
+                }
+                Some(previous) => {
+                    // Nothing can be validated against a pruned round
+                    if let Some(previous) = self.rounds.get(&previous) {
+                        state.validate_pending(quorum, Some(previous.validated()));
+                    }
+                }
+            }
+            self.metrics.rejected_values += state.rejected().len() - rejected;
+            self.rounds.insert(current, state);
+        }
+    }
+
+    /// Ends as many rounds as possible, returning the messages starting the new rounds.
+    fn progress(&mut self, quorum: &dyn QuorumSystem) -> Vec<Message<T>> {
+        let mut outgoing = Vec::new();
+        while !self.awaiting_coin
+            && !self.terminated
+            && self.rounds[&self.round].is_complete(quorum)
+        {
+            let _spans = enter_round(self.round);
+            let validated = self.rounds[&self.round].validated();
+            let selected = selection_protocol::selection_protocol(self.round, quorum, validated);
+            match &selected {
+                Some(value) => {
+                    tracing::debug!(value = ?value.value, decided = value.decided, "selected")
+                }
+                None => tracing::debug!("nothing selected"),
+            }
+            match self.round % 3 {
+                0 => {
+                    self.value = selected.expect("Expected phase stage one to have a majority");
+                }
+                1 => {
+                    self.value = selected.unwrap_or_else(|| self.value.clone());
+                }
+                2 => match selected {
+                    Some(value) if value.decided => {
+                        outgoing.extend(self.decide(value.value.clone()));
+                        self.value = BroadcastValue::new(value.value, false);
+                    }
+                    Some(value) => self.value = value,
+                    None => {
+                        tracing::debug!("awaiting a coin flip");
+                        self.awaiting_coin = true;
+                        break;
+                    }
+                },
+                _ => unreachable!(),
+            }
+            outgoing.extend(self.start_next_round(quorum));
+        }
+        outgoing
+    }
+
+    fn start_next_round(&mut self, quorum: &dyn QuorumSystem) -> Vec<Message<T>> {
+        self.round += 1;
+        let state = RoundState::new(self.id, self.round);
+        let mut outgoing = vec![state.initiate(self.value.clone())];
+        self.rounds.insert(self.round, state);
+        if self.decided.is_some() {
+            self.prune();
+        }
+
+        //Replay messages that arrived before the local round started
+        let _spans = enter_round(self.round);
+        for message in self.early_messages.remove(&self.round).unwrap_or_default() {
+            self.metrics.early_messages -= 1;
+            if let Some(buffered) = self.early_senders.get_mut(&message.sender_id) {
+                *buffered -= 1;
+            }
+            let round = message.round;
+            outgoing.extend(
+                self.rounds
+                    .get_mut(&round)
+                    .unwrap()
+                    .handle_message(quorum, message),
+            );
+        }
+        self.validate_from(quorum, self.round);
+        outgoing
+    }
+}
+
+/// Spans of a round and of its phase, entered until dropped.
+struct RoundSpans {
+    // Fields drop in order, so the round is exited before its phase
+    _round: EnteredSpan,
+    _phase: EnteredSpan,
+}
+
+fn enter_round(round: usize) -> RoundSpans {
+    let phase = tracing::debug_span!("phase", phase = round / 3).entered();
+    let round = tracing::debug_span!("round", round).entered();
+    RoundSpans {
+        _round: round,
+        _phase: phase,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::quorum::ThresholdQuorum;
+
+    fn message(round: usize, sender: usize) -> Message<bool> {
+        let value = BroadcastValue::new(true, false);
+        Message::new(round, sender, 0, value, MessageType::Echo)
+    }
+
+    #[test]
+    fn future_messages_are_bounded() {
+        let quorum = ThresholdQuorum::new(4);
+        let mut state = ConsensusState::new(0, true);
+        let sender_cap = LOOKAHEAD_ROUNDS * (2 * 4 + 1);
+
+        state.handle_message(&quorum, message(LOOKAHEAD_ROUNDS + 1, 1));
+        state.handle_message(&quorum, message(1, 4));
+        for _ in 0..=sender_cap {
+            state.handle_message(&quorum, message(1, 3));
+        }
+        state.handle_message(&quorum, message(LOOKAHEAD_ROUNDS, 2));
+
+        let metrics = state.metrics();
+        assert_eq!(metrics.early_messages, sender_cap + 1);
+        assert_eq!(metrics.dropped_messages, 3);
+    }
+
+    #[test]
+    fn made_up_senders_and_values_take_no_room() {
+        let quorum = ThresholdQuorum::new(4);
+        let mut state = ConsensusState::new(0, 0);
+        for sender in 4..1000 {
+            let value = BroadcastValue::new(0, false);
+            state.handle_message(
+                &quorum,
+                Message::new(1, sender, 0, value, MessageType::Echo),
+            );
+        }
+        assert!(state.early_senders.is_empty());
+        assert_eq!(state.metrics().dropped_messages, 996);
+
+        for value in 0..1000 {
+            let value = BroadcastValue::new(value, true);
+            state.handle_message(&quorum, Message::new(0, 3, 3, value, MessageType::Decide));
+        }
+        assert_eq!(state.decide_senders.len(), 1);
+    }
+
+    #[test]
+    fn decided_processes_prune_old_rounds() {
+        let quorum = ThresholdQuorum::new(4);
+        let mut nodes: Vec<_> = (0..4).map(|id| ConsensusState::new(id, true)).collect();
+        let mut in_flight: VecDeque<_> = nodes.iter().flat_map(ConsensusState::start).collect();
+        while let Some(message) = in_flight.pop_front() {
+            for node in nodes.iter_mut().filter(|node| !node.terminated()) {
+                in_flight.extend(node.handle_message(&quorum, message.clone()));
+            }
+        }
+
+        for node in &nodes {
+            assert_eq!(node.decided(), Some(&true));
+            let metrics = node.metrics();
+            assert!(metrics.rounds <= 2, "{metrics:?}");
+            assert!(metrics.pruned_rounds > 0, "{metrics:?}");
+            assert_eq!(metrics.early_messages, 0);
+        }
+    }
 }
@@ -0,0 +1,186 @@
+use std::{collections::BTreeSet, fmt::Debug};
+
+use crate::util;
+
+/// Trust assumptions used by the broadcast and validation layers.
+///
+/// All queries take the set of distinct process ids that sent a given message and are
+/// answered from the local process' point of view.
+pub trait QuorumSystem: Send + Sync + Debug {
+    fn process_count(&self) -> usize;
+
+    /// Voting power held by `set`, used to rank competing values against each other.
+    fn voting_power(&self, set: &BTreeSet<usize>) -> u64;
+
+    /// Whether `set` contains a quorum, i.e. any two such sets intersect in a correct process.
+    fn is_quorum(&self, set: &BTreeSet<usize>) -> bool;
+
+    /// Whether `set` intersects every quorum, i.e. it contains at least one correct process.
+    fn is_blocking(&self, set: &BTreeSet<usize>) -> bool;
+
+    /// Whether `set` holds more than half of the total voting power.
+    fn is_majority(&self, set: &BTreeSet<usize>) -> bool {
+        let everyone = (0..self.process_count()).collect();
+        2 * self.voting_power(set) > self.voting_power(&everyone)
+    }
+}
+
+/// The classic `f < n / 3` setting where every process counts the same.
+#[derive(Clone, Debug)]
+pub struct ThresholdQuorum {
+    process_count: usize,
+    faulty_count: usize,
+}
+
+impl ThresholdQuorum {
+    pub fn new(process_count: usize) -> ThresholdQuorum {
+        ThresholdQuorum::with_faulty_count(process_count, util::faulty_count(process_count))
+    }
+
+    pub fn with_faulty_count(process_count: usize, faulty_count: usize) -> ThresholdQuorum {
+        assert!(
+            3 * faulty_count < process_count,
+            "Expected fewer than a third of the processes to be faulty"
+        );
+        ThresholdQuorum {
+            process_count,
+            faulty_count,
+        }
+    }
+
+    fn count(&self, set: &BTreeSet<usize>) -> usize {
+        set.range(..self.process_count).count()
+    }
+}
+
+impl QuorumSystem for ThresholdQuorum {
+    fn process_count(&self) -> usize {
+        self.process_count
+    }
+
+    fn voting_power(&self, set: &BTreeSet<usize>) -> u64 {
+        self.count(set) as u64
+    }
+
+    fn is_quorum(&self, set: &BTreeSet<usize>) -> bool {
+        2 * self.count(set) > self.process_count + self.faulty_count
+    }
+
+    fn is_blocking(&self, set: &BTreeSet<usize>) -> bool {
+        self.count(set) > self.faulty_count
+    }
+}
+
+/// Stake-based quorums: each process id carries a weight and the protocol is safe as long
+/// as the total weight of faulty processes is below a third of the total weight.
+/// With every weight set to 1 this behaves like [`ThresholdQuorum`].
+#[derive(Clone, Debug)]
+pub struct WeightedQuorum {
+    weights: Vec<u64>,
+}
+
+impl WeightedQuorum {
+    pub fn new(weights: Vec<u64>) -> WeightedQuorum {
+        assert!(
+            weights.iter().sum::<u64>() > 0,
+            "Expected a positive total weight"
+        );
+        WeightedQuorum { weights }
+    }
+
+    pub fn weight(&self, id: usize) -> u64 {
+        self.weights.get(id).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.weights.iter().sum()
+    }
+
+    /// Largest faulty weight tolerated, i.e. the largest `F` with `3F < W`.
+    pub fn faulty(&self) -> u64 {
+        (self.total() - 1) / 3
+    }
+}
+
+impl QuorumSystem for WeightedQuorum {
+    fn process_count(&self) -> usize {
+        self.weights.len()
+    }
+
+    fn voting_power(&self, set: &BTreeSet<usize>) -> u64 {
+        set.iter().map(|id| self.weight(*id)).sum()
+    }
+
+    fn is_quorum(&self, set: &BTreeSet<usize>) -> bool {
+        2 * self.voting_power(set) > self.total() + self.faulty()
+    }
+
+    fn is_blocking(&self, set: &BTreeSet<usize>) -> bool {
+        self.voting_power(set) > self.faulty()
+    }
+}
+
+/// Asymmetric quorums: the local process declares its own fail-prone sets, any of which
+/// it believes may be entirely faulty. Its quorums are the complements of those sets.
+#[derive(Clone, Debug)]
+pub struct AsymmetricQuorum {
+    process_count: usize,
+    fail_prone_sets: Vec<BTreeSet<usize>>,
+}
+
+impl AsymmetricQuorum {
+    pub fn new(process_count: usize, fail_prone_sets: Vec<BTreeSet<usize>>) -> AsymmetricQuorum {
+        let fail_prone_sets = if fail_prone_sets.is_empty() {
+            vec![BTreeSet::new()]
+        } else {
+            fail_prone_sets
+        };
+        // No three fail-prone sets may cover every process (the local B3 condition)
+        for first in &fail_prone_sets {
+            for second in &fail_prone_sets {
+                for third in &fail_prone_sets {
+                    let covered = (0..process_count).all(|id| {
+                        first.contains(&id) || second.contains(&id) || third.contains(&id)
+                    });
+                    assert!(
+                        !covered,
+                        "Expected no three fail-prone sets to cover all processes"
+                    );
+                }
+            }
+        }
+        AsymmetricQuorum {
+            process_count,
+            fail_prone_sets,
+        }
+    }
+
+    pub fn fail_prone_sets(&self) -> &[BTreeSet<usize>] {
+        &self.fail_prone_sets
+    }
+}
+
+impl QuorumSystem for AsymmetricQuorum {
+    fn process_count(&self) -> usize {
+        self.process_count
+    }
+
+    fn voting_power(&self, set: &BTreeSet<usize>) -> u64 {
+        set.range(..self.process_count).count() as u64
+    }
+
+    fn is_quorum(&self, set: &BTreeSet<usize>) -> bool {
+        // Some quorum `P \ F` is contained in `set`
+        self.fail_prone_sets.iter().any(|fail_prone| {
+            (0..self.process_count).all(|id| set.contains(&id) || fail_prone.contains(&id))
+        })
+    }
+
+    fn is_blocking(&self, set: &BTreeSet<usize>) -> bool {
+        // `set` meets every quorum, so it cannot lie within a single fail-prone set
+        self.fail_prone_sets.iter().all(|fail_prone| {
+            set.range(..self.process_count)
+                .any(|id| !fail_prone.contains(id))
+        })
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    messaging::Message,
+    metrics::{MetricEvent, MetricsSink},
+    node::{ConsensusNode, Step},
+    phase::LOOKAHEAD_ROUNDS,
+    quorum::QuorumSystem,
+    util::Broadcastable,
+};
+
+/// Messages received for slots past the local window, keyed by slot, with their sender.
+type EarlySlotMessages<T> = BTreeMap<usize, Vec<(usize, Message<Option<T>>)>>;
+
+/// What a [`ReplicatedLog`] asks of its runtime after an input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogStep<T> {
+    /// Messages to send to every process, the local one included.
+    pub messages: Vec<Message<Option<T>>>,
+    /// Slots waiting for a coin flip through [`ReplicatedLog::provide_coin`].
+    pub coin_requests: Vec<usize>,
+}
+
+impl<T> Default for LogStep<T> {
+    fn default() -> Self {
+        LogStep {
+            messages: Vec::new(),
+            coin_requests: Vec::new(),
+        }
+    }
+}
+
+/// Sink of the instance of one slot. Instances report under the labels of their process, so
+/// their early messages are summed over every running instance of the log rather than
+/// overwriting each other. Other events pass through: each decided slot is one sample of the
+/// decision histograms.
+#[derive(Debug)]
+struct SlotMetrics {
+    inner: Arc<dyn MetricsSink>,
+    /// Early messages of every running instance of the log.
+    total: Arc<AtomicUsize>,
+    /// Early messages of this instance, as last reported.
+    own: AtomicUsize,
+}
+
+impl MetricsSink for SlotMetrics {
+    fn record(&self, node: usize, event: MetricEvent) {
+        let MetricEvent::EarlyMessages(count) = event else {
+            return self.inner.record(node, event);
+        };
+        let previous = self.own.swap(count, Ordering::Relaxed);
+        self.total.fetch_add(count, Ordering::Relaxed);
+        let total = self.total.fetch_sub(previous, Ordering::Relaxed) - previous;
+        self.inner.record(node, MetricEvent::EarlyMessages(total));
+    }
+}
+
+impl Drop for SlotMetrics {
+    fn drop(&mut self) {
+        self.total.fetch_sub(*self.own.get_mut(), Ordering::Relaxed);
+    }
+}
+
+/// Consensus instance deciding one slot of the log, with the value the local process proposed
+/// for it.
+#[derive(Debug)]
+struct Instance<T> {
+    node: ConsensusNode<Option<T>>,
+    proposal: Option<T>,
+}
+
+/// Totally ordered log of values, built from a consensus instance per slot.
+///
+/// Each slot decides between the values proposed for it. A process with nothing to propose
+/// supports the first value it hears of, or `None` for an empty slot. Up to `window` slots past
+/// the first undecided one run at once, and their decisions are handed to the `deliver` callback
+/// in slot order, empty slots left out. A value that loses its slot is proposed again in the next
+/// free one. Values are not deduplicated.
+///
+/// Like any [`ConsensusNode`], an instance keeps running after deciding until the others no
+/// longer need it. Messages for the `window` slots following the local window are buffered, each
+/// sender getting as much room as a correct process could use there. A process lagging further
+/// behind has to be brought up to date by other means.
+pub struct ReplicatedLog<T, F> {
+    id: usize,
+    quorum: Arc<dyn QuorumSystem>,
+    window: usize,
+    instances: BTreeMap<usize, Instance<T>>,
+    decisions: BTreeMap<usize, Option<T>>,
+    next_delivery: usize,
+    pending: VecDeque<T>,
+    early_messages: EarlySlotMessages<T>,
+    early_senders: BTreeMap<usize, usize>,
+    dropped_messages: usize,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    /// Early messages of the running instances, as last reported to `metrics`.
+    instance_early_messages: Arc<AtomicUsize>,
+    deliver: F,
+}
+
+impl<T, F> ReplicatedLog<T, F>
+where
+    T: Broadcastable,
+    F: FnMut(usize, T),
+{
+    pub fn new(
+        id: usize,
+        quorum: Arc<dyn QuorumSystem>,
+        window: usize,
+        deliver: F,
+    ) -> ReplicatedLog<T, F> {
+        assert!(window > 0, "Expected a window of at least one slot");
+        ReplicatedLog {
+            id,
+            quorum,
+            window,
+            instances: BTreeMap::new(),
+            decisions: BTreeMap::new(),
+            next_delivery: 0,
+            pending: VecDeque::new(),
+            early_messages: BTreeMap::new(),
+            early_senders: BTreeMap::new(),
+            dropped_messages: 0,
+            metrics: None,
+            instance_early_messages: Arc::default(),
+            deliver,
+        }
+    }
+
+    /// Makes the instance of every slot opened from now on report to `metrics`, the early
+    /// messages of all instances being reported as one sum.
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> ReplicatedLog<T, F> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// First slot not delivered yet.
+    pub fn next_delivery(&self) -> usize {
+        self.next_delivery
+    }
+
+    /// Values waiting for a free slot.
+    pub fn pending(&self) -> &VecDeque<T> {
+        &self.pending
+    }
+
+    /// Value the local process proposed for `slot`, if it is still running.
+    pub fn proposal(&self, slot: usize) -> Option<&T> {
+        self.instances.get(&slot)?.proposal.as_ref()
+    }
+
+    /// Slots whose instance is still running.
+    pub fn running_slots(&self) -> impl Iterator<Item = usize> + '_ {
+        self.instances.keys().copied()
+    }
+
+    /// Messages dropped for being too far ahead or over their sender's share of the buffer.
+    pub fn dropped_messages(&self) -> usize {
+        self.dropped_messages
+    }
+
+    /// Appends `value` to the values to order, proposing it as soon as a slot is free.
+    pub fn propose(&mut self, value: T) -> LogStep<T> {
+        self.pending.push_back(value);
+        let mut step = LogStep::default();
+        self.fill(&mut step);
+        step
+    }
+
+    /// Skips the slots before `slot`, whose values the application got by other means such as a
+    /// checkpoint. The instances of skipped slots keep running for the processes that need them.
+    pub fn fast_forward(&mut self, slot: usize) -> LogStep<T> {
+        let mut step = LogStep::default();
+        if slot > self.next_delivery {
+            self.decisions = self.decisions.split_off(&slot);
+            self.next_delivery = slot;
+            self.advance(&mut step);
+        }
+        step
+    }
+
+    /// Processes `message`, received from process `from` as authenticated by the runtime.
+    pub fn handle_message(&mut self, from: usize, message: Message<Option<T>>) -> LogStep<T> {
+        let mut step = LogStep::default();
+        self.dispatch(from, message, &mut step);
+        self.advance(&mut step);
+        step
+    }
+
+    /// Goes on with the outcome of the coin flip requested for `slot`. A random choice between
+    /// the local [`proposal`](ReplicatedLog::proposal) and `None` does.
+    pub fn provide_coin(&mut self, slot: usize, value: Option<T>) -> LogStep<T> {
+        let instance = self
+            .instances
+            .get_mut(&slot)
+            .unwrap_or_else(|| panic!("Expected slot {slot} to be running"));
+        let node_step = instance.node.provide_coin(value);
+        let mut step = LogStep::default();
+        self.absorb(slot, node_step, &mut step);
+        self.advance(&mut step);
+        step
+    }
+
+    fn dispatch(&mut self, from: usize, message: Message<Option<T>>, step: &mut LogStep<T>) {
+        let slot = message.slot;
+        let window_end = self.next_delivery + self.window;
+        if slot >= window_end {
+            self.buffer(from, message);
+            return;
+        }
+        if slot >= self.next_delivery
+            && !self.instances.contains_key(&slot)
+            && !self.decisions.contains_key(&slot)
+        {
+            self.open(slot, message.value.value.clone(), step);
+        }
+        // Slots that terminated are done with, whether delivered or not
+        let Some(instance) = self.instances.get_mut(&slot) else {
+            return;
+        };
+        let node_step = instance.node.handle_message(from, message);
+        self.absorb(slot, node_step, step);
+    }
+
+    /// Keeps a message for one of the `window` slots past the local window, unless its sender
+    /// already buffered more than a correct process could have sent.
+    fn buffer(&mut self, from: usize, message: Message<Option<T>>) {
+        // Without us, a correct process only gets as far as its first round in these slots
+        let process_count = self.quorum.process_count();
+        let sender_cap = self.window * LOOKAHEAD_ROUNDS * (2 * process_count + 2);
+        // Checked before making room for the sender, so that made-up senders take none
+        if message.slot >= self.next_delivery + 2 * self.window || from >= process_count {
+            self.dropped_messages += 1;
+            return;
+        }
+        let buffered = self.early_senders.entry(from).or_default();
+        if *buffered >= sender_cap {
+            self.dropped_messages += 1;
+            return;
+        }
+        *buffered += 1;
+        self.early_messages
+            .entry(message.slot)
+            .or_default()
+            .push((from, message));
+    }
+
+    /// Starts the instance of `slot` with the next pending value, or with `fallback` when there
+    /// is none: empty proposals would otherwise outvote a lone proposer.
+    fn open(&mut self, slot: usize, fallback: Option<T>, step: &mut LogStep<T>) {
+        let proposal = self.pending.pop_front();
+        let initial_value = proposal.clone().or(fallback);
+        let mut node = ConsensusNode::new(self.id, initial_value, self.quorum.clone());
+        if let Some(metrics) = &self.metrics {
+            node = node.with_metrics(Arc::new(SlotMetrics {
+                inner: metrics.clone(),
+                total: self.instance_early_messages.clone(),
+                own: AtomicUsize::new(0),
+            }));
+        }
+        let node_step = node.start();
+        self.instances.insert(slot, Instance { node, proposal });
+        self.absorb(slot, node_step, step);
+    }
+
+    fn absorb(&mut self, slot: usize, node_step: Step<Option<T>>, step: &mut LogStep<T>) {
+        step.messages.extend(
+            node_step
+                .messages
+                .into_iter()
+                .map(|message| message.with_slot(slot)),
+        );
+        // Replayed messages may report the same pending flip more than once
+        if node_step.coin_requested && !step.coin_requests.contains(&slot) {
+            step.coin_requests.push(slot);
+        }
+        if let Some(value) = node_step.decided {
+            let instance = self
+                .instances
+                .get_mut(&slot)
+                .expect("Expected a decided slot to run");
+            if let Some(proposal) = instance.proposal.take() {
+                if value.as_ref() != Some(&proposal) {
+                    self.pending.push_front(proposal);
+                }
+            }
+            if slot >= self.next_delivery {
+                self.decisions.insert(slot, value);
+            }
+        }
+        if node_step.terminated {
+            self.instances.remove(&slot);
+        }
+    }
+
+    /// Delivers the decided prefix of the log, then replays the messages buffered for slots
+    /// that entered the window and proposes pending values in the free slots.
+    fn advance(&mut self, step: &mut LogStep<T>) {
+        loop {
+            while let Some(value) = self.decisions.remove(&self.next_delivery) {
+                if let Some(value) = value {
+                    (self.deliver)(self.next_delivery, value);
+                }
+                self.next_delivery += 1;
+            }
+
+            let later = self
+                .early_messages
+                .split_off(&(self.next_delivery + self.window));
+            let ready = mem::replace(&mut self.early_messages, later);
+            if ready.is_empty() {
+                break;
+            }
+            for (from, message) in ready.into_values().flatten() {
+                *self.early_senders.entry(from).or_default() -= 1;
+                self.dispatch(from, message, step);
+            }
+        }
+        self.fill(step);
+    }
+
+    fn fill(&mut self, step: &mut LogStep<T>) {
+        for slot in self.next_delivery..self.next_delivery + self.window {
+            if self.pending.is_empty() {
+                break;
+            }
+            if !self.instances.contains_key(&slot) && !self.decisions.contains_key(&slot) {
+                self.open(slot, None, step);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        broadcast::BroadcastValue, messaging::MessageType, metrics::Registry,
+        quorum::ThresholdQuorum,
+    };
+
+    type Delivered = Rc<RefCell<Vec<(usize, u32)>>>;
+
+    fn log(
+        id: usize,
+        process_count: usize,
+        window: usize,
+    ) -> (ReplicatedLog<u32, impl FnMut(usize, u32)>, Delivered) {
+        let delivered = Delivered::default();
+        let sink = delivered.clone();
+        let quorum = Arc::new(ThresholdQuorum::new(process_count));
+        let log = ReplicatedLog::new(id, quorum, window, move |slot, value| {
+            sink.borrow_mut().push((slot, value))
+        });
+        (log, delivered)
+    }
+
+    #[test]
+    fn processes_deliver_the_same_log() {
+        let process_count = 4;
+        let mut logs = Vec::new();
+        let mut delivered = Vec::new();
+        let mut in_flight = VecDeque::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        for id in 0..process_count {
+            let (mut log, sink) = log(id, process_count, 2);
+            for command in 0..3 {
+                in_flight.extend(log.propose(10 * id as u32 + command).messages);
+            }
+            logs.push(log);
+            delivered.push(sink);
+        }
+
+        while let Some(message) = in_flight.pop_front() {
+            for log in &mut logs {
+                let mut step = log.handle_message(message.sender_id, message.clone());
+                while let Some(slot) = step.coin_requests.pop() {
+                    let coin = log.proposal(slot).copied().filter(|_| rng.gen_bool(0.5));
+                    let next = log.provide_coin(slot, coin);
+                    step.messages.extend(next.messages);
+                    step.coin_requests.extend(next.coin_requests);
+                }
+                in_flight.extend(step.messages);
+            }
+        }
+
+        let first = delivered[0].borrow().clone();
+        for sink in &delivered {
+            assert_eq!(*sink.borrow(), first);
+        }
+        let mut values: Vec<_> = first.iter().map(|(_, value)| *value).collect();
+        values.sort_unstable();
+        assert_eq!(values, vec![0, 1, 2, 10, 11, 12, 20, 21, 22, 30, 31, 32]);
+        assert!(logs.iter().all(|log| log.pending().is_empty()));
+    }
+
+    #[test]
+    fn messages_past_the_window_are_buffered_then_dropped() {
+        let (mut log, _) = log(0, 4, 2);
+        let value = BroadcastValue::new(Some(7), false);
+        let message =
+            |slot| Message::new(0, 1, 1, value.clone(), MessageType::Initiate).with_slot(slot);
+
+        assert_eq!(log.handle_message(1, message(3)), LogStep::default());
+        assert_eq!(log.running_slots().count(), 0);
+        assert_eq!(log.dropped_messages(), 0);
+        log.handle_message(1, message(4));
+        assert_eq!(log.dropped_messages(), 1);
+
+        // Made-up senders take no room at all
+        for sender in 4..100 {
+            log.handle_message(sender, message(3));
+        }
+        assert_eq!(log.dropped_messages(), 97);
+        assert_eq!(log.early_senders.len(), 1);
+
+        // Once slot 0 opens, the buffered message waits for the window to reach slot 3
+        let step = log.handle_message(1, message(0));
+        assert!(step.messages.iter().all(|message| message.slot == 0));
+        assert_eq!(log.running_slots().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn instances_report_the_sum_of_their_early_messages() {
+        let registry = Arc::new(Registry::new(4));
+        let total = Arc::new(AtomicUsize::new(0));
+        let slot = || SlotMetrics {
+            inner: registry.clone(),
+            total: total.clone(),
+            own: AtomicUsize::new(0),
+        };
+        let (first, second) = (slot(), slot());
+        let early_messages = |count| {
+            registry
+                .render()
+                .contains(&format!("consensus_early_messages{{node=\"1\"}} {count}\n"))
+        };
+
+        first.record(1, MetricEvent::EarlyMessages(3));
+        second.record(1, MetricEvent::EarlyMessages(2));
+        assert!(early_messages(5));
+        first.record(1, MetricEvent::EarlyMessages(1));
+        assert!(early_messages(3));
+        drop(first);
+        second.record(1, MetricEvent::EarlyMessages(2));
+        assert!(early_messages(2));
+    }
+}
@@ -1,139 +1,124 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    hash::Hash,
-    thread,
-};
-
-use crossbeam::{
-    channel::{self, Receiver, Sender},
-    select,
-};
+use std::collections::{BTreeMap, BTreeSet};
 
 use crate::{
-    broadcast::{self, BroadcastSender, BroadcastValue},
-    messaging::Message,
-    util::Broadcastable, validation::ValidatedMessageSet,
+    broadcast::{BroadcastState, BroadcastValue},
+    messaging::{Message, MessageType},
+    quorum::QuorumSystem,
+    util::Broadcastable,
+    validation::ValidatedMessageSet,
 };
 
-pub fn round<T>(
+/// Event-driven state of one round at one process: a reliable broadcast instance per process
+/// and the delivered values, which count once they have been validated.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RoundState<T> {
     round: usize,
-    process_id: usize,
-    initial_value: BroadcastValue<T>,
-    process_count: usize,
-    early_messages: HashMap<usize, Vec<Message<T>>>,
-    previously_validated: ValidatedMessageSet<T>,
-    receiver: Receiver<Message<T>>,
-    sender: BroadcastSender<T>,
-) -> (
-    ValidatedMessageSet<T>,
-    HashMap<usize, Vec<Message<T>>>,
-    Receiver<Message<T>>,
-)
+    id: usize,
+    instances: BTreeMap<usize, BroadcastState<T>>,
+    pending: BTreeMap<usize, BroadcastValue<T>>,
+    rejected: BTreeSet<usize>,
+    validated: ValidatedMessageSet<T>,
+}
+
+impl<T> RoundState<T>
 where
     T: Broadcastable,
 {
-
-    //Initiate broadcast for each process
-    let mut internal_senders = Vec::with_capacity(process_count);
-    let mut handles = Vec::with_capacity(process_count);
-
-    for index in 0..process_count {
-        let (s, r) = channel::unbounded();
-        internal_senders.push(s);
-
-        let sender_clone = sender.clone();
-        if index == process_id {
-            //Broadcast initialize message
-            let initial_value_clone = initial_value.clone();
-            handles.push(thread::spawn(move || {
-                broadcast::local_broadcast(
-                    round,
-                    process_id,
-                    initial_value_clone,
-                    process_count,
-                    r,
-                    sender_clone,
-                )
-            }))
-        } else {
-            handles.push(thread::spawn(move || {
-                broadcast::broadcast_protocol(process_count, r, sender_clone)
-            }));
+    pub fn new(id: usize, round: usize) -> RoundState<T> {
+        RoundState {
+            round,
+            id,
+            instances: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            rejected: BTreeSet::new(),
+            validated: ValidatedMessageSet::new(),
         }
     }
 
-    // Start router to forward external messages to proper thread (necessary due to constraining channels to mpsc functionality)
-    let (router_sender, router_receiver) = channel::bounded(1);
-    let router_handle = thread::spawn(move || {
-        route_messages(
-            process_id,
-            round,
-            early_messages,
-            receiver,
-            internal_senders,
-            router_receiver,
-        )
-    });
+    pub fn round(&self) -> usize {
+        self.round
+    }
 
-    // Collect results
-    let mut validated = ValidatedMessageSet::new();
-    for handle in handles {
-        let accepted = handle.join().unwrap();
-        if previously_validated.validate(round, process_count, &accepted) {
-            validated.add(accepted);
-        }
+    pub fn validated(&self) -> &ValidatedMessageSet<T> {
+        &self.validated
     }
 
-    //Terminate router
-    router_sender.send("exit".to_string());
-    let (early_messages, receiver) = router_handle.join().unwrap();
+    /// Delivered values, by broadcast source, that could not be validated yet.
+    pub fn pending(&self) -> &BTreeMap<usize, BroadcastValue<T>> {
+        &self.pending
+    }
 
-    (validated, early_messages, receiver)
-}
+    /// Sources of the delivered values that failed their first validation attempt, whether they
+    /// were validated later or not.
+    pub fn rejected(&self) -> &BTreeSet<usize> {
+        &self.rejected
+    }
 
-fn route_messages<T>(
-    id: usize,
-    round: usize,
-    mut early_messages: HashMap<usize, Vec<Message<T>>>,
-    external_receiver: Receiver<Message<T>>,
-    internal_senders: Vec<Sender<Message<T>>>,
-    terminate_receiver: Receiver<String>,
-) -> (HashMap<usize, Vec<Message<T>>>, Receiver<Message<T>>)
-where T: Broadcastable
- {
+    /// Message starting the local process' own broadcast for this round.
+    pub fn initiate(&self, value: BroadcastValue<T>) -> Message<T> {
+        Message::new(self.round, self.id, self.id, value, MessageType::Initiate)
+    }
+
+    /// Routes `message` to its broadcast instance and returns the messages to send to every
+    /// process. Delivered values wait in `pending` until [`RoundState::validate_pending`] accepts
+    /// them.
+    pub fn handle_message(
+        &mut self,
+        quorum: &dyn QuorumSystem,
+        message: Message<T>,
+    ) -> Vec<Message<T>> {
+        let source = message.broadcast_source_id;
+        if source >= quorum.process_count() || message.sender_id >= quorum.process_count() {
+            return Vec::new();
+        }
 
-    //Forward messages that arrived before the local round started
-    if let Some(messages) = early_messages.remove(&round) {
-        for message in messages {
-            forward(message, &internal_senders);
+        let instance = self
+            .instances
+            .entry(source)
+            .or_insert_with(|| BroadcastState::new(self.id));
+        let _span = tracing::debug_span!("broadcast", source).entered();
+        let already_delivered = instance.delivered().is_some();
+        let outgoing = instance.handle_message(quorum, message);
+        if let (false, Some(value)) = (already_delivered, instance.delivered()) {
+            self.pending.insert(source, value.clone());
         }
+        outgoing
     }
 
-    loop {
-        //TODO end condition?
+    /// Validates pending values against the previous round's validated values, or accepts every
+    /// undecided value in the very first round. Returns whether anything was accepted.
+    pub fn validate_pending(
+        &mut self,
+        quorum: &dyn QuorumSystem,
+        previously_validated: Option<&ValidatedMessageSet<T>>,
+    ) -> bool {
+        let round = self.round;
+        let accepted: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, value)| match previously_validated {
+                Some(previous) => previous.validate(round, quorum, value),
+                None => !value.decided,
+            })
+            .map(|(source, _)| *source)
+            .collect();
 
-        select! {
-            recv(external_receiver) -> message => {
-                let message = message.unwrap();
-                if message.round == round {
-                    forward(message, &internal_senders)
-                }
-                else if message.round > round {
-                    let entry = early_messages.entry(message.round).or_insert(Vec::new());
-                    entry.push(message);
-                }
-            }
-            recv(terminate_receiver) -> _ => {
-            return (early_messages, external_receiver)
+        for source in &accepted {
+            let value = self.pending.remove(source).unwrap();
+            tracing::debug!(source, value = ?value.value, decided = value.decided, "value validated");
+            self.validated.add(*source, value);
+        }
+        // Rejected values stay pending, as a larger previous set may still validate them
+        for (source, value) in &self.pending {
+            if self.rejected.insert(*source) {
+                tracing::trace!(source, value = ?value.value, decided = value.decided, "value rejected");
             }
         }
+        !accepted.is_empty()
     }
-}
 
-fn forward<T>(message: Message<T>, senders: &Vec<Sender<Message<T>>>) {
-    if let Some(sender) = senders.get(message.broadcast_source_id) {
-        sender.send(message);
-    } else {
-        panic!()
+    /// Whether a quorum of values has been validated, so the round can end.
+    pub fn is_complete(&self, quorum: &dyn QuorumSystem) -> bool {
+        self.validated.is_complete(quorum)
     }
 }
@@ -0,0 +1,228 @@
+use std::{fs, path::Path, time::Duration};
+
+use serde::Deserialize;
+
+use crate::simulation::{
+    Coin, Fault, InitialValues, NetworkConditions, RunReport, Runtime, SimulationConfig,
+};
+
+/// Experiment checked into a TOML or JSON file, along with the outcome it should have.
+///
+/// ```toml
+/// name = "equivocators against a split vote"
+/// nodes = 7
+/// faults = ["equivocate", "repeat:false:decided"]
+/// initial = "split"
+/// coin = "common"
+/// seed = 3
+///
+/// [network]
+/// delay = { min = 1, max = 20 }
+/// partitions = [{ nodes = [0, 1], from = 0, until = 200 }]
+/// max_time = 10000
+///
+/// [expect]
+/// value = false
+/// max_phase = 4
+/// ```
+///
+/// The last processes are the faulty ones, one per entry of `faults`. Scenarios run in the
+/// simulator unless `transport = "threads"`, which does not support network conditions.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default)]
+    pub name: String,
+    pub nodes: usize,
+    #[serde(default)]
+    pub faults: Vec<Fault>,
+    pub initial: InitialValues,
+    #[serde(default = "default_coin")]
+    pub coin: Coin,
+    #[serde(default = "default_transport")]
+    pub transport: Runtime,
+    #[serde(default)]
+    pub seed: u64,
+    /// Seconds the honest processes get to decide with threads. Simulated runs stop at the
+    /// `max_time` of their network instead.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub network: NetworkConditions,
+    #[serde(default)]
+    pub expect: Expectations,
+}
+
+fn default_coin() -> Coin {
+    Coin::Local
+}
+
+fn default_transport() -> Runtime {
+    Runtime::Simulator
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+/// Outcome a [`Scenario`] should have.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Expectations {
+    /// Whether every honest process decides the same value, or some of them never decide.
+    pub agreement: bool,
+    /// Value the honest processes agree on.
+    pub value: Option<bool>,
+    /// Phase by which every honest process decides.
+    pub max_phase: Option<usize>,
+}
+
+impl Default for Expectations {
+    fn default() -> Self {
+        Expectations {
+            agreement: true,
+            value: None,
+            max_phase: None,
+        }
+    }
+}
+
+impl Scenario {
+    /// Reads a scenario, as JSON if the file name ends with `.json` and as TOML otherwise.
+    pub fn load(path: &Path) -> Result<Scenario, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| format!("Cannot read {}: {error}", path.display()))?;
+        let scenario = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&text).map_err(|error| error.to_string())
+        } else {
+            toml::from_str(&text).map_err(|error| error.to_string())
+        };
+        scenario.map_err(|error| format!("Invalid scenario {}: {error}", path.display()))
+    }
+
+    pub fn config(&self) -> SimulationConfig {
+        SimulationConfig {
+            process_count: self.nodes,
+            faults: self.faults.clone(),
+            initial_values: self.initial,
+            coin: self.coin,
+            runtime: self.transport,
+            seed: self.seed,
+            timeout: Duration::from_secs(self.timeout),
+            network: self.network.clone(),
+        }
+    }
+}
+
+impl Expectations {
+    /// Every way `report` falls short of the expectations.
+    pub fn failures(&self, report: &RunReport) -> Vec<String> {
+        let mut failures = Vec::new();
+        let agreement = report.agreement();
+        if agreement.is_some() != self.agreement {
+            failures.push(match agreement {
+                Some(value) => format!("Expected no agreement, got {value}"),
+                None => "Expected every honest process to decide the same value".to_string(),
+            });
+        }
+        if let (Some(expected), Some(value)) = (self.value, agreement) {
+            if expected != value {
+                failures.push(format!("Expected to agree on {expected}, got {value}"));
+            }
+        }
+        if let Some(max_phase) = self.max_phase {
+            let late = report
+                .nodes
+                .iter()
+                .filter(|node| node.phase.is_some_and(|phase| phase > max_phase));
+            for node in late {
+                failures.push(format!(
+                    "Expected process {} to decide by phase {max_phase}, got {:?}",
+                    node.id, node.phase
+                ));
+            }
+        }
+        failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::simulation::{self, NodeReport};
+
+    #[test]
+    fn toml_and_json_describe_the_same_scenario() {
+        let from_toml: Scenario = toml::from_str(
+            r#"
+            nodes = 4
+            faults = ["silent"]
+            initial = "same:true"
+
+            [network]
+            partitions = [{ nodes = [0], until = 50 }]
+
+            [expect]
+            value = true
+            "#,
+        )
+        .unwrap();
+        let from_json: Scenario = serde_json::from_str(
+            r#"{
+                "nodes": 4,
+                "faults": ["silent"],
+                "initial": "same:true",
+                "network": { "partitions": [{ "nodes": [0], "until": 50 }] },
+                "expect": { "value": true }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(from_toml, from_json);
+        assert_eq!(from_toml.transport, Runtime::Simulator);
+
+        let report = simulation::run(&from_toml.config());
+        assert_eq!(from_toml.expect.failures(&report), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unmet_expectations_are_reported() {
+        let report = RunReport {
+            nodes: vec![
+                NodeReport {
+                    id: 0,
+                    fault: None,
+                    initial_value: Some(false),
+                    decided: Some(true),
+                    phase: Some(3),
+                    messages_sent: 0,
+                    messages_received: 0,
+                    wall_time: None,
+                },
+                NodeReport {
+                    id: 1,
+                    fault: None,
+                    initial_value: Some(true),
+                    decided: Some(true),
+                    phase: Some(1),
+                    messages_sent: 0,
+                    messages_received: 0,
+                    wall_time: None,
+                },
+            ],
+            elapsed: Duration::ZERO,
+            stragglers: BTreeSet::new(),
+        };
+        let expect = Expectations {
+            agreement: true,
+            value: Some(false),
+            max_phase: Some(2),
+        };
+        assert_eq!(expect.failures(&report).len(), 2);
+        assert!(toml::from_str::<Scenario>("nodes = 4\ninitial = \"half\"").is_err());
+    }
+}
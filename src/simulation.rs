@@ -0,0 +1,896 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap},
+    fmt,
+    str::FromStr,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    broadcast::BroadcastValue,
+    byz_protocol::{self, Decision},
+    cluster::ClusterHandle,
+    faulty,
+    messaging::Message,
+    network::{self, ChannelConfig},
+    node::ConsensusNode,
+    quorum::{QuorumSystem, ThresholdQuorum},
+    trace::{Trace, TraceEvent},
+    util::{self, NetworkInfo},
+};
+
+/// Time the threads get to stop once a threaded run is over.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+const VALUES: [BroadcastValue<bool>; 4] = [
+    BroadcastValue {
+        value: false,
+        decided: false,
+    },
+    BroadcastValue {
+        value: true,
+        decided: false,
+    },
+    BroadcastValue {
+        value: false,
+        decided: true,
+    },
+    BroadcastValue {
+        value: true,
+        decided: true,
+    },
+];
+
+/// Behaviour of a faulty process.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Fault {
+    /// Never sends anything.
+    Silent,
+    /// Sends the same value for every broadcast and announces it as decided, see
+    /// [`faulty::faulty_process`].
+    Repeat(BroadcastValue<bool>),
+    /// Sends random values, different for every recipient, see
+    /// [`faulty::equivocating_process`].
+    Equivocate,
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    /// Parses `silent`, `equivocate` or `repeat:<value>[:decided]`.
+    fn from_str(text: &str) -> Result<Fault, String> {
+        let parts: Vec<_> = text.split(':').collect();
+        match parts.as_slice() {
+            ["silent"] => Ok(Fault::Silent),
+            ["equivocate"] => Ok(Fault::Equivocate),
+            ["repeat", value] => Ok(Fault::Repeat(BroadcastValue::new(
+                parse_bool(value)?,
+                false,
+            ))),
+            ["repeat", value, "decided"] => {
+                Ok(Fault::Repeat(BroadcastValue::new(parse_bool(value)?, true)))
+            }
+            _ => Err(format!(
+                "Unknown fault {text}, expected silent, equivocate or repeat:<value>[:decided]"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Fault {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Fault, String> {
+        text.parse()
+    }
+}
+
+impl Serialize for Fault {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Silent => write!(f, "silent"),
+            Fault::Equivocate => write!(f, "equivocate"),
+            Fault::Repeat(value) if value.decided => write!(f, "repeat:{}:decided", value.value),
+            Fault::Repeat(value) => write!(f, "repeat:{}", value.value),
+        }
+    }
+}
+
+/// How initial values are spread over the honest processes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum InitialValues {
+    Same(bool),
+    /// Half of the processes start with `false`, the other half with `true`.
+    Split,
+    /// Drawn from the run's seed.
+    Random,
+}
+
+impl FromStr for InitialValues {
+    type Err = String;
+
+    /// Parses `same:<value>`, `split` or `random`.
+    fn from_str(text: &str) -> Result<InitialValues, String> {
+        match text.split_once(':') {
+            Some(("same", value)) => Ok(InitialValues::Same(parse_bool(value)?)),
+            None if text == "split" => Ok(InitialValues::Split),
+            None if text == "random" => Ok(InitialValues::Random),
+            _ => Err(format!(
+                "Unknown initial values {text}, expected same:<value>, split or random"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for InitialValues {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitialValues::Same(value) => write!(f, "same:{value}"),
+            InitialValues::Split => write!(f, "split"),
+            InitialValues::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl TryFrom<String> for InitialValues {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<InitialValues, String> {
+        text.parse()
+    }
+}
+
+fn parse_bool(text: &str) -> Result<bool, String> {
+    text.parse()
+        .map_err(|_| format!("Expected true or false, got {text}"))
+}
+
+/// Where the coin flips of a phase come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Coin {
+    /// Every process flips its own coin.
+    Local,
+    /// Every process gets the same flip for a given phase.
+    Common,
+}
+
+/// How processes run and exchange messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime {
+    /// A thread per process, connected by in-memory queues.
+    Threads,
+    /// Every process in the calling thread, messages being delivered after delays drawn from the
+    /// run's seed, under the configured network conditions.
+    Simulator,
+}
+
+/// Time a message spends in transit in the simulator, drawn uniformly from `min..=max` ticks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Delay {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        Delay { min: 1, max: 10 }
+    }
+}
+
+/// Cuts `nodes` off from the other processes for the ticks `from..until`. Messages across the
+/// cut arrive once it heals, or never if it does not.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Partition {
+    pub nodes: BTreeSet<usize>,
+    #[serde(default)]
+    pub from: u64,
+    pub until: Option<u64>,
+}
+
+impl Partition {
+    fn separates(&self, from: usize, to: usize, arrival: u64) -> bool {
+        self.nodes.contains(&from) != self.nodes.contains(&to)
+            && self.from <= arrival
+            && self.until.is_none_or(|until| arrival < until)
+    }
+}
+
+/// Network the simulator runs processes over, in virtual ticks.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConditions {
+    pub delay: Delay,
+    pub partitions: Vec<Partition>,
+    /// Tick past which the simulator stops delivering messages, so that a seeded run ends the
+    /// same way however fast the machine running it is.
+    pub max_time: u64,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        NetworkConditions {
+            delay: Delay::default(),
+            partitions: Vec::new(),
+            max_time: 100_000,
+        }
+    }
+}
+
+/// Everything a run depends on. Processes `0..honest_count()` are honest, the following ones
+/// behave as given by `faults`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimulationConfig {
+    pub process_count: usize,
+    pub faults: Vec<Fault>,
+    pub initial_values: InitialValues,
+    pub coin: Coin,
+    pub runtime: Runtime,
+    pub seed: u64,
+    /// Time the honest processes get to decide with [`Runtime::Threads`]. The simulator stops at
+    /// the `max_time` of its network instead.
+    pub timeout: Duration,
+    /// Only simulated by [`Runtime::Simulator`].
+    pub network: NetworkConditions,
+}
+
+impl SimulationConfig {
+    pub fn honest_count(&self) -> usize {
+        self.process_count - self.faults.len()
+    }
+
+    /// Initial value of each honest process.
+    pub fn initial_values(&self) -> Vec<bool> {
+        let honest_count = self.honest_count();
+        match self.initial_values {
+            InitialValues::Same(value) => vec![value; honest_count],
+            InitialValues::Split => (0..honest_count).map(|id| id % 2 == 1).collect(),
+            InitialValues::Random => {
+                let mut rng = StdRng::seed_from_u64(self.seed);
+                (0..honest_count).map(|_| rng.gen_bool(0.5)).collect()
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.process_count == 0 {
+            return Err("Expected at least one process".to_string());
+        }
+        let max_faulty = util::faulty_count(self.process_count);
+        if self.faults.len() > max_faulty {
+            return Err(format!(
+                "At most {max_faulty} of {} processes can be faulty",
+                self.process_count
+            ));
+        }
+        if self.runtime == Runtime::Threads && self.network != NetworkConditions::default() {
+            return Err("Network conditions are only simulated by the simulator".to_string());
+        }
+        let delay = self.network.delay;
+        if delay.min > delay.max {
+            return Err(format!(
+                "Expected a minimal delay of at most {}, got {}",
+                delay.max, delay.min
+            ));
+        }
+        let unknown = self
+            .network
+            .partitions
+            .iter()
+            .flat_map(|partition| &partition.nodes)
+            .find(|id| **id >= self.process_count);
+        if let Some(id) = unknown {
+            return Err(format!("Unknown process {id} in a partition"));
+        }
+        Ok(())
+    }
+}
+
+/// How one process ran.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct NodeReport {
+    pub id: usize,
+    /// Behaviour of the process if it is faulty.
+    pub fault: Option<Fault>,
+    /// Value an honest process started with.
+    pub initial_value: Option<bool>,
+    pub decided: Option<bool>,
+    pub phase: Option<usize>,
+    pub messages_sent: usize,
+    pub messages_received: usize,
+    /// Wall time from the start of the run until the process terminated, if it did. Processes
+    /// keep running after they decide until a quorum of decision announcements reaches them, so
+    /// this is later than their decision in both runtimes.
+    #[serde(serialize_with = "serialize_optional_seconds")]
+    pub wall_time: Option<Duration>,
+}
+
+impl NodeReport {
+    fn new(config: &SimulationConfig, id: usize) -> NodeReport {
+        let honest_count = config.honest_count();
+        NodeReport {
+            id,
+            fault: id
+                .checked_sub(honest_count)
+                .and_then(|index| config.faults.get(index))
+                .cloned(),
+            initial_value: config.initial_values().get(id).copied(),
+            decided: None,
+            phase: None,
+            messages_sent: 0,
+            messages_received: 0,
+            wall_time: None,
+        }
+    }
+
+    pub fn is_honest(&self) -> bool {
+        self.fault.is_none()
+    }
+
+    fn decide(&mut self, decision: Decision<bool>) {
+        self.decided = Some(decision.value);
+        self.phase = Some(decision.phase);
+    }
+}
+
+/// Outcome of a run, by process id.
+///
+/// Displays as a table, serializes to JSON, and renders as CSV through [`RunReport::to_csv`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RunReport {
+    pub nodes: Vec<NodeReport>,
+    #[serde(serialize_with = "serialize_seconds")]
+    pub elapsed: Duration,
+    /// Threads of the threads runtime still running once the run was over, left detached.
+    pub stragglers: BTreeSet<usize>,
+}
+
+const COLUMNS: [&str; 8] = [
+    "id",
+    "role",
+    "initial",
+    "decided",
+    "phase",
+    "sent",
+    "received",
+    "wall_time_ms",
+];
+
+impl RunReport {
+    /// Value every honest process decided, if they all decided the same.
+    pub fn agreement(&self) -> Option<bool> {
+        let mut honest = self.nodes.iter().filter(|node| node.is_honest());
+        let first = honest.next()?.decided?;
+        honest
+            .all(|node| node.decided == Some(first))
+            .then_some(first)
+    }
+
+    /// A header line, then a line per process.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::new();
+        for row in self.rows() {
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        let header = COLUMNS.iter().map(|column| column.to_string()).collect();
+        let rows = self.nodes.iter().map(|node| {
+            vec![
+                node.id.to_string(),
+                node.fault
+                    .as_ref()
+                    .map_or_else(|| "honest".to_string(), Fault::to_string),
+                optional(node.initial_value.map(|value| value.to_string())),
+                optional(node.decided.map(|value| value.to_string())),
+                optional(node.phase.map(|phase| phase.to_string())),
+                node.messages_sent.to_string(),
+                node.messages_received.to_string(),
+                optional(
+                    node.wall_time
+                        .map(|time| format!("{:.3}", time.as_secs_f64() * 1000.0)),
+                ),
+            ]
+        });
+        std::iter::once(header).chain(rows).collect()
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self.rows();
+        let widths: Vec<_> = (0..rows[0].len())
+            .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap())
+            .collect();
+        for row in &rows {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect();
+            writeln!(f, "{}", cells.join("  ").trim_end())?;
+        }
+        match self.agreement() {
+            Some(value) => write!(f, "Agreed on {value} in {:?}", self.elapsed),
+            None => write!(f, "No agreement after {:?}", self.elapsed),
+        }
+    }
+}
+
+fn serialize_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+fn serialize_optional_seconds<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration
+        .map(|duration| duration.as_secs_f64())
+        .serialize(serializer)
+}
+
+/// Coin of one process.
+struct CoinSource {
+    coin: Coin,
+    rng: StdRng,
+    /// Flips of the common coin drawn so far, by phase. Every process draws them from the run's
+    /// seed in the same order, so they all get the same flip for a phase.
+    common: Vec<bool>,
+    common_rng: StdRng,
+}
+
+impl CoinSource {
+    fn new(coin: Coin, seed: u64, id: usize) -> CoinSource {
+        CoinSource {
+            coin,
+            rng: StdRng::seed_from_u64(seed.wrapping_add(id as u64)),
+            common: Vec::new(),
+            common_rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    fn flip(&mut self, phase: usize) -> bool {
+        match self.coin {
+            Coin::Local => self.rng.gen_bool(0.5),
+            Coin::Common => {
+                while self.common.len() <= phase {
+                    self.common.push(self.common_rng.gen_bool(0.5));
+                }
+                self.common[phase]
+            }
+        }
+    }
+}
+
+/// Runs the configured experiment.
+pub fn run(config: &SimulationConfig) -> RunReport {
+    let start = Instant::now();
+    let (nodes, stragglers) = match config.runtime {
+        Runtime::Threads => run_threads(config, start),
+        Runtime::Simulator => (
+            simulate(config, config.initial_values(), false).reports,
+            BTreeSet::new(),
+        ),
+    };
+    RunReport {
+        nodes,
+        elapsed: start.elapsed(),
+        stragglers,
+    }
+}
+
+/// Runs the configured experiment in the simulator, whatever its runtime, recording every
+/// message sent and delivered along with the coin flips and decisions of the honest processes.
+pub fn run_traced(config: &SimulationConfig) -> (RunReport, Trace) {
+    let start = Instant::now();
+    let simulated = simulate(config, config.initial_values(), true);
+    let report = RunReport {
+        nodes: simulated.reports,
+        elapsed: start.elapsed(),
+        stragglers: BTreeSet::new(),
+    };
+    (
+        report,
+        simulated
+            .trace
+            .expect("Expected the simulator to record a trace"),
+    )
+}
+
+/// Reports of the processes, with the threads still running once the run is over.
+fn run_threads(config: &SimulationConfig, start: Instant) -> (Vec<NodeReport>, BTreeSet<usize>) {
+    let process_count = config.process_count;
+    let honest_count = config.honest_count();
+    let initial_values = config.initial_values();
+    let endpoints = network::connect(process_count, ChannelConfig::for_cluster(process_count));
+    // Kept to read the traffic counters once the run is over
+    let senders: Vec<_> = endpoints
+        .iter()
+        .map(|(senders, _)| senders.clone())
+        .collect();
+    let (results, decisions) = mpsc::channel();
+    let mut cluster = ClusterHandle::new();
+
+    for (id, (senders, inbox)) in endpoints.into_iter().enumerate() {
+        let network = NetworkInfo::new(id, senders, inbox).with_cancellation(cluster.token());
+        let seed = config.seed.wrapping_add(id as u64);
+        if id < honest_count {
+            let initial_value = initial_values[id];
+            let mut coin = CoinSource::new(config.coin, config.seed, id);
+            let results = results.clone();
+            cluster.spawn(id, move || {
+                let decision = byz_protocol::consensus_with_coin(
+                    initial_value,
+                    |phase| coin.flip(phase),
+                    network,
+                )?;
+                // Only returns once the process terminated, as timed by the simulator
+                let _ = results.send((id, decision.clone(), start.elapsed()));
+                Some(decision.value)
+            });
+            continue;
+        }
+        match config.faults[id - honest_count].clone() {
+            Fault::Silent => {}
+            Fault::Repeat(value) => cluster.spawn(id, move || {
+                faulty::faulty_process(value, network);
+                None
+            }),
+            Fault::Equivocate => cluster.spawn(id, move || {
+                faulty::equivocating_process(VALUES.to_vec(), seed, network);
+                None
+            }),
+        }
+    }
+
+    let mut nodes: Vec<_> = (0..process_count)
+        .map(|id| NodeReport::new(config, id))
+        .collect();
+    let deadline = start + config.timeout;
+    for _ in 0..honest_count {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match decisions.recv_timeout(remaining) {
+            Ok((id, decision, wall_time)) => {
+                nodes[id].decide(decision);
+                nodes[id].wall_time = Some(wall_time);
+            }
+            Err(_) => break,
+        }
+    }
+    let report = cluster.shutdown(SHUTDOWN_DEADLINE);
+
+    for (from, senders) in senders.iter().enumerate() {
+        for (to, sender) in senders.iter().enumerate() {
+            let stats = sender.stats();
+            nodes[from].messages_sent += stats.queued + stats.received + stats.dropped;
+            nodes[to].messages_received += stats.received;
+        }
+    }
+    (nodes, report.stragglers)
+}
+
+/// Message due at a tick, ordered by its sending rank among messages due at the same tick.
+type Delivery = Reverse<(u64, u64, usize, Message<bool>)>;
+
+/// Messages in transit in the simulator, delivered by arrival tick then by sending order.
+struct SimulatedNetwork {
+    conditions: NetworkConditions,
+    process_count: usize,
+    rng: StdRng,
+    now: u64,
+    sequence: u64,
+    in_flight: BinaryHeap<Delivery>,
+    sent: Vec<usize>,
+    received: Vec<usize>,
+    trace: Option<Trace>,
+}
+
+impl SimulatedNetwork {
+    fn record(&mut self, event: impl FnOnce(u64) -> TraceEvent) {
+        if let Some(trace) = &mut self.trace {
+            trace.events.push(event(self.now));
+        }
+    }
+
+    fn send(&mut self, to: usize, message: Message<bool>) {
+        self.sent[message.sender_id] += 1;
+        self.record(|time| TraceEvent::Send {
+            time,
+            from: message.sender_id,
+            to,
+            message: message.clone(),
+        });
+        let delay = self.conditions.delay;
+        let mut arrival = self.now + self.rng.gen_range(delay.min..=delay.max);
+        for partition in &self.conditions.partitions {
+            if partition.separates(message.sender_id, to, arrival) {
+                match partition.until {
+                    Some(until) => arrival = until,
+                    None => return,
+                }
+            }
+        }
+        self.sequence += 1;
+        self.in_flight
+            .push(Reverse((arrival, self.sequence, to, message)));
+    }
+
+    fn broadcast(&mut self, messages: Vec<Message<bool>>) {
+        for message in messages {
+            for to in 0..self.process_count {
+                self.send(to, message.clone());
+            }
+        }
+    }
+
+    /// Delivers the next message, unless none is left before `max_time`.
+    fn next(&mut self) -> Option<(usize, Message<bool>)> {
+        let Reverse((arrival, ..)) = self.in_flight.peek()?;
+        if *arrival > self.conditions.max_time {
+            return None;
+        }
+        let Reverse((arrival, _, to, message)) = self.in_flight.pop()?;
+        self.now = arrival;
+        self.received[to] += 1;
+        self.record(|time| TraceEvent::Deliver {
+            time,
+            from: message.sender_id,
+            to,
+            message: message.clone(),
+        });
+        Some((to, message))
+    }
+}
+
+/// Where a simulated run ended up, see [`simulate`].
+#[derive(Debug)]
+pub struct Simulated {
+    /// State machines of the honest processes, by id.
+    pub nodes: Vec<ConsensusNode<bool>>,
+    pub reports: Vec<NodeReport>,
+    pub trace: Option<Trace>,
+}
+
+/// Runs every process of `config` in the calling thread, honest ones starting with
+/// `initial_values` whatever the configured ones, until the honest ones terminate or no message
+/// is left to deliver by the `max_time` of the network; the timeout of `config` does not apply.
+/// Faulty processes react to the first message of each round by sending to every process, as
+/// in [`faulty`]. A trace of the run is kept if `record_trace` is set.
+///
+/// # Panics
+///
+/// If there is not an initial value for each honest process.
+pub fn simulate(
+    config: &SimulationConfig,
+    initial_values: Vec<bool>,
+    record_trace: bool,
+) -> Simulated {
+    let start = Instant::now();
+    let process_count = config.process_count;
+    let honest_count = config.honest_count();
+    assert_eq!(
+        initial_values.len(),
+        honest_count,
+        "Expected an initial value for each honest process"
+    );
+    let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(process_count));
+    let mut network = SimulatedNetwork {
+        conditions: config.network.clone(),
+        process_count,
+        rng: StdRng::seed_from_u64(config.seed),
+        now: 0,
+        sequence: 0,
+        in_flight: BinaryHeap::new(),
+        sent: vec![0; process_count],
+        received: vec![0; process_count],
+        trace: record_trace.then(|| Trace::new(process_count)),
+    };
+
+    let mut nodes: Vec<_> = initial_values
+        .iter()
+        .enumerate()
+        .map(|(id, value)| ConsensusNode::new(id, *value, quorum.clone()))
+        .collect();
+    let mut coins: Vec<_> = (0..honest_count)
+        .map(|id| CoinSource::new(config.coin, config.seed, id))
+        .collect();
+    let mut reports: Vec<_> = (0..process_count)
+        .map(|id| NodeReport::new(config, id))
+        .collect();
+    for (report, value) in reports.iter_mut().zip(&initial_values) {
+        report.initial_value = Some(*value);
+    }
+    let mut faulty_rounds = vec![0; process_count - honest_count];
+    for node in &nodes {
+        network.record(|time| TraceEvent::Start {
+            time,
+            node: node.id(),
+            initial_value: node.state().value().value,
+        });
+        network.broadcast(node.start().messages);
+    }
+
+    while nodes.iter().any(|node| !node.terminated()) {
+        let Some((to, message)) = network.next() else {
+            break;
+        };
+        if to < honest_count {
+            let node = &mut nodes[to];
+            let mut step = node.handle_message(message.sender_id, message);
+            loop {
+                if let Some(value) = step.decided {
+                    let phase = node.state().phase();
+                    reports[to].decide(Decision { value, phase });
+                    network.record(|time| TraceEvent::Decide {
+                        time,
+                        node: to,
+                        phase,
+                        value,
+                    });
+                }
+                network.broadcast(step.messages);
+                if !step.coin_requested {
+                    break;
+                }
+                let phase = node.state().phase();
+                let value = coins[to].flip(phase);
+                network.record(|time| TraceEvent::Coin {
+                    time,
+                    node: to,
+                    phase,
+                    value,
+                });
+                step = node.provide_coin(value);
+            }
+            if node.terminated() && reports[to].wall_time.is_none() {
+                reports[to].wall_time = Some(start.elapsed());
+            }
+            continue;
+        }
+
+        let fault = &config.faults[to - honest_count];
+        let round = &mut faulty_rounds[to - honest_count];
+        if *fault == Fault::Silent || message.round != *round {
+            continue;
+        }
+        *round += 1;
+        for recipient in 0..process_count {
+            for source in 0..process_count {
+                for message_type in faulty::MESSAGE_TYPES {
+                    let value = match fault {
+                        Fault::Repeat(value) => value.clone(),
+                        _ => VALUES.choose(&mut network.rng).unwrap().clone(),
+                    };
+                    let message = Message::new(message.round, to, source, value, message_type);
+                    network.send(recipient, message);
+                }
+            }
+        }
+    }
+
+    for (id, report) in reports.iter_mut().enumerate() {
+        report.messages_sent = network.sent[id];
+        report.messages_received = network.received[id];
+    }
+    Simulated {
+        nodes,
+        reports,
+        trace: network.trace,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(runtime: Runtime) -> SimulationConfig {
+        SimulationConfig {
+            process_count: 7,
+            faults: vec![Fault::Equivocate, "repeat:false:decided".parse().unwrap()],
+            initial_values: InitialValues::Same(true),
+            coin: Coin::Common,
+            runtime,
+            seed: 3,
+            timeout: Duration::from_secs(30),
+            network: NetworkConditions::default(),
+        }
+    }
+
+    #[test]
+    fn options_round_trip() {
+        for text in [
+            "silent",
+            "equivocate",
+            "repeat:true",
+            "repeat:false:decided",
+        ] {
+            assert_eq!(text.parse::<Fault>().unwrap().to_string(), text);
+        }
+        for text in ["same:false", "split", "random"] {
+            assert_eq!(text.parse::<InitialValues>().unwrap().to_string(), text);
+        }
+        assert!("repeat:maybe".parse::<Fault>().is_err());
+    }
+
+    #[test]
+    fn runtimes_agree_on_unanimous_values() {
+        for runtime in [Runtime::Threads, Runtime::Simulator] {
+            let report = run(&config(runtime));
+            assert_eq!(report.agreement(), Some(true), "{report}");
+        }
+    }
+
+    #[test]
+    fn simulated_runs_are_bounded_by_virtual_time_only() {
+        let mut config = SimulationConfig {
+            timeout: Duration::ZERO,
+            ..config(Runtime::Simulator)
+        };
+        let report = run(&config);
+        assert_eq!(report.agreement(), Some(true), "{report}");
+
+        // Too early for any process to have gone through the three rounds of a phase
+        config.network.max_time = 5;
+        let report = run(&config);
+        assert!(
+            report.nodes.iter().all(|node| node.decided.is_none()),
+            "{report}"
+        );
+    }
+
+    #[test]
+    fn reports_cover_every_process() {
+        let report = run(&config(Runtime::Simulator));
+        assert_eq!(report.nodes.len(), 7);
+        for node in &report.nodes[..5] {
+            assert!(node.is_honest() && node.wall_time.is_some(), "{report}");
+            assert!(node.messages_sent > 0 && node.messages_received > 0);
+        }
+        assert_eq!(report.nodes[5].fault, Some(Fault::Equivocate));
+
+        let csv = report.to_csv();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], COLUMNS.join(","));
+        assert!(
+            lines[7].starts_with("6,repeat:false:decided,-,-,-,"),
+            "{csv}"
+        );
+    }
+
+    #[test]
+    fn simulated_runs_replay_from_their_trace() {
+        let config = SimulationConfig {
+            initial_values: InitialValues::Split,
+            ..config(Runtime::Simulator)
+        };
+        let (report, trace) = run_traced(&config);
+        let nodes = crate::trace::replay(&trace).unwrap();
+        assert_eq!(nodes.len(), 5);
+        for node in report.nodes.iter().filter(|node| node.is_honest()) {
+            let replayed = nodes[&node.id].state();
+            assert_eq!(replayed.decided().copied(), node.decided);
+            assert_eq!(Some(replayed.phase()), node.phase);
+        }
+    }
+}
@@ -0,0 +1,668 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    sync::Arc,
+};
+
+use crossbeam::channel::{self, Receiver};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::Serialize;
+
+use crate::{
+    messaging::Message,
+    metrics::MetricsSink,
+    quorum::QuorumSystem,
+    replicated_log::{LogStep, ReplicatedLog},
+    util::Broadcastable,
+};
+
+/// Deterministic state machine replicated by every [`Replica`].
+pub trait Application {
+    type Command: Broadcastable + Serialize;
+    type Response: Broadcastable;
+    type Snapshot: Clone + Debug + Send;
+
+    fn apply(&mut self, command: Self::Command) -> Self::Response;
+
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Replaces the whole state with one taken by [`Application::snapshot`].
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+/// Command submitted by a client. Clients have a single request outstanding at a time, number
+/// them from 1 on without gaps, and sign them so that replicas cannot make requests up.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Request<C> {
+    pub client_id: usize,
+    pub sequence: u64,
+    pub command: C,
+    /// Ed25519 signature of the client over the other fields.
+    pub signature: Vec<u8>,
+}
+
+impl<C> Request<C>
+where
+    C: Serialize,
+{
+    /// Bytes the client signs.
+    fn payload(&self) -> Vec<u8> {
+        serde_json::to_vec(&(self.client_id, self.sequence, &self.command))
+            .expect("Expected commands to serialize")
+    }
+}
+
+/// Public keys of the clients replicas serve, by client id.
+#[derive(Clone, Debug, Default)]
+pub struct ClientKeys {
+    keys: BTreeMap<usize, VerifyingKey>,
+}
+
+impl ClientKeys {
+    pub fn new() -> ClientKeys {
+        ClientKeys::default()
+    }
+
+    pub fn insert(&mut self, client_id: usize, key: VerifyingKey) {
+        self.keys.insert(client_id, key);
+    }
+
+    /// Whether `request` carries the signature of its client. Requests of unknown clients never
+    /// do.
+    pub fn verify<C>(&self, request: &Request<C>) -> bool
+    where
+        C: Serialize,
+    {
+        let Some(key) = self.keys.get(&request.client_id) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(&request.signature) else {
+            return false;
+        };
+        key.verify(&request.payload(), &signature).is_ok()
+    }
+}
+
+/// Response of one replica to a request.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Reply<R> {
+    pub replica_id: usize,
+    pub client_id: usize,
+    pub sequence: u64,
+    pub response: R,
+}
+
+/// Message exchanged by replicas ordering requests.
+pub type ReplicaMessage<C> = Message<Option<Request<C>>>;
+
+/// Log callback handing delivered requests back to their [`Replica`].
+type Delivery<C> = Box<dyn FnMut(usize, Request<C>) + Send>;
+
+/// Latest request applied for each client, with its response.
+type ClientTable<R> = BTreeMap<usize, (u64, R)>;
+
+/// What a [`Replica`] asks of its runtime after an input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicaStep<C, R> {
+    /// Messages to send to every replica, the local one included.
+    pub messages: Vec<ReplicaMessage<C>>,
+    /// Slots waiting for a coin flip through [`Replica::provide_coin`].
+    pub coin_requests: Vec<usize>,
+    /// Replies to send to their clients.
+    pub replies: Vec<Reply<R>>,
+}
+
+impl<C, R> Default for ReplicaStep<C, R> {
+    fn default() -> Self {
+        ReplicaStep {
+            messages: Vec::new(),
+            coin_requests: Vec::new(),
+            replies: Vec::new(),
+        }
+    }
+}
+
+impl<C, R> ReplicaStep<C, R> {
+    pub fn extend(&mut self, other: ReplicaStep<C, R>) {
+        self.messages.extend(other.messages);
+        self.coin_requests.extend(other.coin_requests);
+        self.replies.extend(other.replies);
+    }
+}
+
+/// State of a replica once it applied every slot before `slot`, for bringing up to date a
+/// replica lagging too far behind to catch up through the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint<S, R> {
+    pub slot: usize,
+    pub state: S,
+    pub clients: ClientTable<R>,
+}
+
+/// One replica of an [`Application`], applying requests in the order of a [`ReplicatedLog`].
+///
+/// Requests can be submitted to any replica, and every replica replies once it applies them.
+/// A request ordered more than once, for instance because its client submitted it to several
+/// replicas, is only applied the first time. Requests without a valid signature of a known
+/// client are neither proposed nor applied, and a request is only applied right after the
+/// previous one of its client, so a faulty replica can neither forge requests nor lock a client
+/// out.
+pub struct Replica<A>
+where
+    A: Application,
+{
+    id: usize,
+    app: A,
+    log: ReplicatedLog<Request<A::Command>, Delivery<A::Command>>,
+    delivered: Receiver<(usize, Request<A::Command>)>,
+    client_keys: Arc<ClientKeys>,
+    clients: ClientTable<A::Response>,
+}
+
+impl<A> Replica<A>
+where
+    A: Application,
+{
+    pub fn new(
+        id: usize,
+        quorum: Arc<dyn QuorumSystem>,
+        client_keys: Arc<ClientKeys>,
+        window: usize,
+        app: A,
+    ) -> Replica<A> {
+        let (sender, delivered) = channel::unbounded();
+        let deliver: Delivery<A::Command> = Box::new(move |slot, request| {
+            sender
+                .send((slot, request))
+                .expect("Expected the replica to outlive its log");
+        });
+        Replica {
+            id,
+            app,
+            log: ReplicatedLog::new(id, quorum, window, deliver),
+            delivered,
+            client_keys,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    /// Makes the consensus instances ordering the requests report to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> Replica<A> {
+        self.log = self.log.with_metrics(metrics);
+        self
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn app(&self) -> &A {
+        &self.app
+    }
+
+    pub fn into_app(self) -> A {
+        self.app
+    }
+
+    /// First slot not applied yet.
+    pub fn next_slot(&self) -> usize {
+        self.log.next_delivery()
+    }
+
+    /// Orders `request`, or replies right away if it was the last one applied for its client.
+    /// Requests its client did not sign are dropped.
+    pub fn submit(&mut self, request: Request<A::Command>) -> ReplicaStep<A::Command, A::Response> {
+        if !self.client_keys.verify(&request) {
+            return ReplicaStep::default();
+        }
+        match self.clients.get(&request.client_id) {
+            Some((sequence, response)) if *sequence == request.sequence => ReplicaStep {
+                replies: vec![self.reply(&request, response.clone())],
+                ..ReplicaStep::default()
+            },
+            Some((sequence, _)) if *sequence > request.sequence => ReplicaStep::default(),
+            _ => {
+                let log_step = self.log.propose(request);
+                self.finish(log_step)
+            }
+        }
+    }
+
+    /// Processes `message`, received from replica `from` as authenticated by the runtime.
+    pub fn handle_message(
+        &mut self,
+        from: usize,
+        message: ReplicaMessage<A::Command>,
+    ) -> ReplicaStep<A::Command, A::Response> {
+        let log_step = self.log.handle_message(from, message);
+        self.finish(log_step)
+    }
+
+    /// Goes on with a coin flip for `slot`, choosing between the local proposal and an empty
+    /// slot.
+    pub fn provide_coin(
+        &mut self,
+        slot: usize,
+        coin: bool,
+    ) -> ReplicaStep<A::Command, A::Response> {
+        let value = self.log.proposal(slot).filter(|_| coin).cloned();
+        let log_step = self.log.provide_coin(slot, value);
+        self.finish(log_step)
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint<A::Snapshot, A::Response> {
+        Checkpoint {
+            slot: self.next_slot(),
+            state: self.app.snapshot(),
+            clients: self.clients.clone(),
+        }
+    }
+
+    /// Takes the state of `checkpoint` if it is ahead of the local one, and goes on ordering
+    /// from there.
+    pub fn restore(
+        &mut self,
+        checkpoint: Checkpoint<A::Snapshot, A::Response>,
+    ) -> ReplicaStep<A::Command, A::Response> {
+        if checkpoint.slot <= self.next_slot() {
+            return ReplicaStep::default();
+        }
+        self.app.restore(checkpoint.state);
+        self.clients = checkpoint.clients;
+        let log_step = self.log.fast_forward(checkpoint.slot);
+        self.finish(log_step)
+    }
+
+    /// Applies the requests the log delivered during `log_step`.
+    fn finish(
+        &mut self,
+        log_step: LogStep<Request<A::Command>>,
+    ) -> ReplicaStep<A::Command, A::Response> {
+        let mut step = ReplicaStep {
+            messages: log_step.messages,
+            coin_requests: log_step.coin_requests,
+            replies: Vec::new(),
+        };
+        while let Ok((_, request)) = self.delivered.try_recv() {
+            if let Some(reply) = self.apply(request) {
+                step.replies.push(reply);
+            }
+        }
+        step
+    }
+
+    /// Applies `request` if it is the next one of its client. Faulty replicas can get any
+    /// request ordered, so the checks of `submit` are made again.
+    fn apply(&mut self, request: Request<A::Command>) -> Option<Reply<A::Response>> {
+        let last = self
+            .clients
+            .get(&request.client_id)
+            .map_or(0, |(sequence, _)| *sequence);
+        if request.sequence != last + 1 || !self.client_keys.verify(&request) {
+            return None;
+        }
+        let response = self.app.apply(request.command.clone());
+        self.clients
+            .insert(request.client_id, (request.sequence, response.clone()));
+        Some(self.reply(&request, response))
+    }
+
+    fn reply(&self, request: &Request<A::Command>, response: A::Response) -> Reply<A::Response> {
+        Reply {
+            replica_id: self.id,
+            client_id: request.client_id,
+            sequence: request.sequence,
+            response,
+        }
+    }
+}
+
+/// Client of replicated [`Application`]s, accepting a response once a blocking set of replicas
+/// sent it: with `f + 1` matching replies out of `3f + 1`, at least one is from a correct
+/// replica.
+#[derive(Clone, Debug)]
+pub struct Client<R> {
+    id: usize,
+    key: SigningKey,
+    quorum: Arc<dyn QuorumSystem>,
+    sequence: u64,
+    answered: bool,
+    responses: BTreeMap<R, BTreeSet<usize>>,
+}
+
+impl<R> Client<R>
+where
+    R: Broadcastable,
+{
+    /// Client signing its requests with `key`, whose public half the replicas know it by.
+    pub fn new(id: usize, key: SigningKey, quorum: Arc<dyn QuorumSystem>) -> Client<R> {
+        Client {
+            id,
+            key,
+            quorum,
+            sequence: 0,
+            answered: false,
+            responses: BTreeMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Key replicas check the requests of the client with.
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    /// Sequence number of the current request.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Starts a new request, to be submitted to one or more replicas. Replies to earlier ones
+    /// are ignored from then on.
+    pub fn request<C>(&mut self, command: C) -> Request<C>
+    where
+        C: Serialize,
+    {
+        self.sequence += 1;
+        self.answered = false;
+        self.responses.clear();
+        let mut request = Request {
+            client_id: self.id,
+            sequence: self.sequence,
+            command,
+            signature: Vec::new(),
+        };
+        request.signature = self.key.sign(&request.payload()).to_vec();
+        request
+    }
+
+    /// Processes a reply, whose `replica_id` was authenticated by the runtime, and returns the
+    /// response to the current request the first time enough replicas agree on it.
+    pub fn handle_reply(&mut self, reply: Reply<R>) -> Option<R> {
+        if reply.client_id != self.id
+            || reply.sequence != self.sequence
+            || reply.replica_id >= self.quorum.process_count()
+            || self.answered
+        {
+            return None;
+        }
+        let replicas = self.responses.entry(reply.response.clone()).or_default();
+        replicas.insert(reply.replica_id);
+        if !self.quorum.is_blocking(replicas) {
+            return None;
+        }
+        self.answered = true;
+        Some(reply.response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, time::Duration};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        byz_protocol,
+        cluster::ClusterHandle,
+        network::{self, ChannelConfig},
+        quorum::ThresholdQuorum,
+        util::NetworkInfo,
+    };
+
+    /// Running total of the numbers added to it.
+    #[derive(Debug, Default)]
+    struct Counter {
+        total: u64,
+    }
+
+    impl Application for Counter {
+        type Command = u64;
+        type Response = u64;
+        type Snapshot = u64;
+
+        fn apply(&mut self, command: u64) -> u64 {
+            self.total += command;
+            self.total
+        }
+
+        fn snapshot(&self) -> u64 {
+            self.total
+        }
+
+        fn restore(&mut self, snapshot: u64) {
+            self.total = snapshot;
+        }
+    }
+
+    fn client(id: usize, process_count: usize) -> Client<u64> {
+        let key = SigningKey::from_bytes(&[id as u8; 32]);
+        Client::new(id, key, Arc::new(ThresholdQuorum::new(process_count)))
+    }
+
+    /// Keys of the clients made by [`client`] with ids up to `client_count`.
+    fn client_keys(client_count: usize) -> Arc<ClientKeys> {
+        let mut keys = ClientKeys::new();
+        for id in 0..client_count {
+            keys.insert(id, client(id, 1).verifying_key());
+        }
+        Arc::new(keys)
+    }
+
+    struct Cluster {
+        replicas: Vec<Replica<Counter>>,
+        in_flight: VecDeque<ReplicaMessage<u64>>,
+        replies: Vec<Reply<u64>>,
+        rng: StdRng,
+    }
+
+    impl Cluster {
+        fn new(process_count: usize) -> Cluster {
+            let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(process_count));
+            let keys = client_keys(1);
+            Cluster {
+                replicas: (0..process_count)
+                    .map(|id| Replica::new(id, quorum.clone(), keys.clone(), 2, Counter::default()))
+                    .collect(),
+                in_flight: VecDeque::new(),
+                replies: Vec::new(),
+                rng: StdRng::seed_from_u64(0),
+            }
+        }
+
+        fn carry_out(&mut self, id: usize, mut step: ReplicaStep<u64, u64>) {
+            while let Some(slot) = step.coin_requests.pop() {
+                let coin = self.rng.gen_bool(0.5);
+                step.extend(self.replicas[id].provide_coin(slot, coin));
+            }
+            self.in_flight.extend(step.messages);
+            self.replies.extend(step.replies);
+        }
+
+        fn submit(&mut self, id: usize, request: Request<u64>) {
+            let step = self.replicas[id].submit(request);
+            self.carry_out(id, step);
+        }
+
+        fn run(&mut self) {
+            while let Some(message) = self.in_flight.pop_front() {
+                for id in 0..self.replicas.len() {
+                    let step = self.replicas[id].handle_message(message.sender_id, message.clone());
+                    self.carry_out(id, step);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clients_get_the_response_of_a_blocking_set() {
+        let mut cluster = Cluster::new(4);
+        let mut client = client(0, 4);
+        let mut responses = Vec::new();
+        for command in 1..=3 {
+            let request = client.request(command);
+            cluster.submit(command as usize % 4, request);
+            cluster.run();
+            let replies = std::mem::take(&mut cluster.replies);
+            responses.extend(
+                replies
+                    .into_iter()
+                    .filter_map(|reply| client.handle_reply(reply)),
+            );
+        }
+
+        assert_eq!(responses, vec![1, 3, 6]);
+        assert!(cluster
+            .replicas
+            .iter()
+            .all(|replica| replica.app().total == 6));
+    }
+
+    #[test]
+    fn requests_submitted_to_every_replica_are_applied_once() {
+        let mut cluster = Cluster::new(4);
+        let mut client = client(0, 4);
+        let request = client.request(5);
+        for id in 0..4 {
+            cluster.submit(id, request.clone());
+        }
+        cluster.run();
+
+        assert!(cluster
+            .replicas
+            .iter()
+            .all(|replica| replica.app().total == 5));
+        assert_eq!(cluster.replies.len(), 4);
+        // Submitting it again only repeats the reply
+        cluster.submit(0, request);
+        assert_eq!(cluster.in_flight.len(), 0);
+        assert_eq!(cluster.replies.len(), 5);
+    }
+
+    #[test]
+    fn requests_forged_by_a_faulty_replica_are_not_applied() {
+        let mut cluster = Cluster::new(4);
+        let mut client = client(0, 4);
+        // Replica 3 orders requests of client 0 it made up, skipping its own checks
+        let mut forger =
+            Client::<u64>::new(0, SigningKey::from_bytes(&[3; 32]), client.quorum.clone());
+        let unsigned = Request {
+            client_id: 0,
+            sequence: u64::MAX,
+            command: 100,
+            signature: Vec::new(),
+        };
+        for forged in [unsigned, forger.request(100)] {
+            let log_step = cluster.replicas[3].log.propose(forged);
+            let step = cluster.replicas[3].finish(log_step);
+            cluster.carry_out(3, step);
+        }
+        cluster.run();
+        assert!(cluster
+            .replicas
+            .iter()
+            .all(|replica| replica.app().total == 0));
+        assert!(cluster.replies.is_empty());
+
+        // The client is not locked out
+        let request = client.request(5);
+        cluster.submit(0, request);
+        cluster.run();
+        let replies = std::mem::take(&mut cluster.replies);
+        let responses: Vec<_> = replies
+            .into_iter()
+            .filter_map(|reply| client.handle_reply(reply))
+            .collect();
+        assert_eq!(responses, vec![5]);
+    }
+
+    #[test]
+    fn only_the_next_request_of_a_client_is_applied() {
+        let mut cluster = Cluster::new(4);
+        let mut client = client(0, 4);
+        let first = client.request(1);
+        client.request(2);
+        let skipping = client.request(4);
+        // A faulty replica orders the third request before the second was submitted
+        let log_step = cluster.replicas[3].log.propose(skipping);
+        let step = cluster.replicas[3].finish(log_step);
+        cluster.carry_out(3, step);
+        cluster.submit(0, first);
+        cluster.run();
+
+        assert!(cluster
+            .replicas
+            .iter()
+            .all(|replica| replica.app().total == 1));
+    }
+
+    #[test]
+    fn restoring_a_checkpoint_catches_up() {
+        let mut cluster = Cluster::new(4);
+        let mut client = client(0, 4);
+        let requests: Vec<_> = (1..=2).map(|command| client.request(command)).collect();
+        for request in &requests {
+            cluster.submit(0, request.clone());
+            cluster.run();
+        }
+        let checkpoint = cluster.replicas[0].checkpoint();
+
+        let quorum = Arc::new(ThresholdQuorum::new(4));
+        let mut fresh = Replica::new(3, quorum, client_keys(1), 2, Counter::default());
+        fresh.restore(checkpoint.clone());
+        assert_eq!(fresh.checkpoint(), checkpoint);
+        // Requests already applied are not applied again
+        let step = fresh.submit(requests[0].clone());
+        assert_eq!(step, ReplicaStep::default());
+    }
+
+    #[test]
+    fn threaded_replicas_answer_clients() {
+        let process_count = 4;
+        let endpoints = network::connect(process_count, ChannelConfig::for_cluster(process_count));
+        let (reply_sender, replies) = channel::unbounded();
+        let mut request_senders = Vec::new();
+        let mut cluster = ClusterHandle::new();
+        for (id, (senders, inbox)) in endpoints.into_iter().enumerate() {
+            let network = NetworkInfo::new(id, senders, inbox).with_cancellation(cluster.token());
+            let (request_sender, requests) = channel::unbounded();
+            request_senders.push(request_sender);
+            let reply_sender = reply_sender.clone();
+            cluster.spawn(id, move || {
+                let coin = || rand::thread_rng().gen_bool(0.5);
+                let app = Counter::default();
+                let keys = client_keys(1);
+                let app = byz_protocol::replica_protocol(
+                    app,
+                    keys,
+                    2,
+                    coin,
+                    requests,
+                    reply_sender,
+                    network,
+                );
+                Some(app.total)
+            });
+        }
+
+        let mut client = client(0, process_count);
+        for command in [2, 3] {
+            let request = client.request(command);
+            request_senders[0].send(request).unwrap();
+            let response = loop {
+                let reply = replies.recv_timeout(Duration::from_secs(30)).unwrap();
+                if let Some(response) = client.handle_reply(reply) {
+                    break response;
+                }
+            };
+            assert_eq!(response, if command == 2 { 2 } else { 5 });
+        }
+
+        let report = cluster.shutdown(Duration::from_secs(5));
+        assert!(report.is_clean(), "{report:?}");
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    simulation::{
+        self, Coin, Fault, InitialValues, NetworkConditions, RunReport, Runtime, SimulationConfig,
+    },
+    util,
+};
+
+/// Grid of simulations, every point running once per seed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sweep {
+    pub nodes: Vec<usize>,
+    /// Faulty process counts, the largest tolerated one for each process count if empty. Counts
+    /// beyond what a process count tolerates are skipped.
+    pub faulty: Vec<usize>,
+    /// Behaviour of every faulty process.
+    pub fault: Fault,
+    pub initial_values: Vec<InitialValues>,
+    pub coin: Coin,
+    /// Runs per point, with seeds `0..seeds`.
+    pub seeds: u64,
+    /// Conditions of every run, which stops at their `max_time`.
+    pub network: NetworkConditions,
+}
+
+/// Parameters shared by the runs of one point of a [`Sweep`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GridPoint {
+    pub nodes: usize,
+    pub faulty: usize,
+    pub initial_values: InitialValues,
+}
+
+/// Mean and percentiles of a distribution.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    pub mean: f64,
+    pub p50: f64,
+    pub p99: f64,
+}
+
+impl Summary {
+    /// Summary of `values`, by nearest rank, unless there are none.
+    pub fn of(mut values: Vec<f64>) -> Option<Summary> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        let percentile = |fraction: f64| {
+            let rank = (fraction * values.len() as f64).ceil() as usize;
+            values[rank.max(1) - 1]
+        };
+        Some(Summary {
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+        })
+    }
+}
+
+/// Aggregated runs of one point of a [`Sweep`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SweepRow {
+    pub point: GridPoint,
+    pub runs: usize,
+    /// Runs in which every honest process decided the same value.
+    pub agreements: usize,
+    /// Phase in which each honest process decided, over every run.
+    pub phases: Option<Summary>,
+    /// Messages sent by all processes per honest decision, by run.
+    pub messages_per_decision: Option<Summary>,
+}
+
+const COLUMNS: [&str; 11] = [
+    "nodes",
+    "faulty",
+    "initial",
+    "runs",
+    "agreements",
+    "phase_mean",
+    "phase_p50",
+    "phase_p99",
+    "messages_per_decision_mean",
+    "messages_per_decision_p50",
+    "messages_per_decision_p99",
+];
+
+impl SweepRow {
+    fn new(point: GridPoint, reports: &[RunReport]) -> SweepRow {
+        let phases = reports
+            .iter()
+            .flat_map(|report| &report.nodes)
+            .filter_map(|node| node.phase)
+            .map(|phase| phase as f64)
+            .collect();
+        let messages_per_decision = reports
+            .iter()
+            .filter_map(|report| {
+                let decisions = report.nodes.iter().filter(|node| node.decided.is_some());
+                let decisions = decisions.count();
+                let messages: usize = report.nodes.iter().map(|node| node.messages_sent).sum();
+                (decisions > 0).then(|| messages as f64 / decisions as f64)
+            })
+            .collect();
+        SweepRow {
+            point,
+            runs: reports.len(),
+            agreements: reports
+                .iter()
+                .filter(|report| report.agreement().is_some())
+                .count(),
+            phases: Summary::of(phases),
+            messages_per_decision: Summary::of(messages_per_decision),
+        }
+    }
+}
+
+/// A header line, then a line per row. Distributions without a sample are left empty.
+pub fn to_csv(rows: &[SweepRow]) -> String {
+    let summary = |summary: Option<Summary>| match summary {
+        Some(summary) => format!("{},{},{}", summary.mean, summary.p50, summary.p99),
+        None => ",,".to_string(),
+    };
+    let mut csv = COLUMNS.join(",");
+    csv.push('\n');
+    for row in rows {
+        let point = row.point;
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            point.nodes,
+            point.faulty,
+            point.initial_values,
+            row.runs,
+            row.agreements,
+            summary(row.phases),
+            summary(row.messages_per_decision),
+        ));
+    }
+    csv
+}
+
+impl Sweep {
+    /// Faulty counts requested for each process count, tolerated or not.
+    fn faulty_counts(&self, nodes: usize) -> Vec<usize> {
+        match self.faulty.as_slice() {
+            [] => vec![util::faulty_count(nodes)],
+            counts => counts.to_vec(),
+        }
+    }
+
+    /// Every point of the grid, by process count, then faulty count, then initial values.
+    pub fn points(&self) -> Vec<GridPoint> {
+        let mut points = Vec::new();
+        for &nodes in &self.nodes {
+            let max_faulty = util::faulty_count(nodes);
+            let faulty = self.faulty_counts(nodes);
+            for faulty in faulty.into_iter().filter(|faulty| *faulty <= max_faulty) {
+                for &initial_values in &self.initial_values {
+                    points.push(GridPoint {
+                        nodes,
+                        faulty,
+                        initial_values,
+                    });
+                }
+            }
+        }
+        points
+    }
+
+    /// Requested `(nodes, faulty)` pairs left out of the grid because `nodes` processes cannot
+    /// tolerate `faulty` faulty ones.
+    pub fn skipped(&self) -> Vec<(usize, usize)> {
+        self.nodes
+            .iter()
+            .flat_map(|&nodes| {
+                let max_faulty = util::faulty_count(nodes);
+                self.faulty_counts(nodes)
+                    .into_iter()
+                    .filter(move |faulty| *faulty > max_faulty)
+                    .map(move |faulty| (nodes, faulty))
+            })
+            .collect()
+    }
+
+    /// Checks that the grid has points and that each of them can run, see
+    /// [`SimulationConfig::validate`].
+    pub fn validate(&self) -> Result<(), String> {
+        if self.nodes.contains(&0) {
+            return Err("Expected at least one process at every point".to_string());
+        }
+        if self.seeds == 0 {
+            return Err("Expected at least one seed".to_string());
+        }
+        let points = self.points();
+        if points.is_empty() {
+            return Err("No point of the grid tolerates its faulty processes".to_string());
+        }
+        points
+            .into_iter()
+            .try_for_each(|point| self.config(point, 0).validate())
+    }
+
+    fn config(&self, point: GridPoint, seed: u64) -> SimulationConfig {
+        SimulationConfig {
+            process_count: point.nodes,
+            faults: vec![self.fault.clone(); point.faulty],
+            initial_values: point.initial_values,
+            coin: self.coin,
+            runtime: Runtime::Simulator,
+            seed,
+            // Only bounds threaded runs
+            timeout: Duration::ZERO,
+            network: self.network.clone(),
+        }
+    }
+
+    /// Runs every point with every seed in the simulator, spread over `threads` threads, and
+    /// aggregates the runs of each point, unless the sweep is not valid.
+    pub fn run(&self, threads: usize) -> Result<Vec<SweepRow>, String> {
+        self.validate()?;
+        let points = self.points();
+        let runs: Vec<_> = points
+            .iter()
+            .flat_map(|point| (0..self.seeds).map(|seed| self.config(*point, seed)))
+            .collect();
+        let next = AtomicUsize::new(0);
+        let reports = Mutex::new(vec![None; runs.len()]);
+        thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(config) = runs.get(index) else {
+                        break;
+                    };
+                    let report = simulation::run(config);
+                    reports.lock().unwrap()[index] = Some(report);
+                });
+            }
+        });
+
+        let reports: Vec<_> = reports
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|report| report.expect("Expected every run to report"))
+            .collect();
+        Ok(points
+            .into_iter()
+            .zip(reports.chunks(self.seeds as usize))
+            .map(|(point, reports)| SweepRow::new(point, reports))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let summary = Summary::of((1..=100).map(f64::from).collect()).unwrap();
+        assert_eq!(summary.mean, 50.5);
+        assert_eq!((summary.p50, summary.p99), (50.0, 99.0));
+        assert_eq!(Summary::of(vec![3.0]).unwrap().p99, 3.0);
+        assert_eq!(Summary::of(Vec::new()), None);
+    }
+
+    #[test]
+    fn sweeps_aggregate_every_point() {
+        let sweep = Sweep {
+            nodes: vec![4, 7],
+            faulty: vec![0, 2],
+            fault: Fault::Equivocate,
+            initial_values: vec![InitialValues::Same(true), InitialValues::Split],
+            coin: Coin::Common,
+            seeds: 3,
+            network: NetworkConditions::default(),
+        };
+        let rows = sweep.run(2).unwrap();
+        // 4 processes cannot tolerate 2 faulty ones
+        assert_eq!(sweep.skipped(), vec![(4, 2)]);
+        assert_eq!(rows.len(), 6);
+        assert!(rows.iter().all(|row| row.runs == 3 && row.agreements == 3));
+        assert!(rows.iter().all(|row| row.phases.unwrap().p50 >= 0.0));
+
+        let csv = to_csv(&rows);
+        assert_eq!(csv.lines().count(), 7);
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("4,0,same:true,3,3,"));
+    }
+
+    #[test]
+    fn sweeps_without_runnable_points_are_rejected() {
+        let mut sweep = Sweep {
+            nodes: vec![0],
+            faulty: Vec::new(),
+            fault: Fault::Silent,
+            initial_values: vec![InitialValues::Split],
+            coin: Coin::Local,
+            seeds: 1,
+            network: NetworkConditions::default(),
+        };
+        assert!(sweep.run(1).is_err());
+
+        sweep.nodes = vec![4];
+        sweep.faulty = vec![2];
+        assert_eq!(sweep.skipped(), vec![(4, 2)]);
+        assert!(sweep.run(1).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, Write},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    messaging::Message,
+    node::{ConsensusNode, Step},
+    quorum::{QuorumSystem, ThresholdQuorum},
+};
+
+/// Something that happened during a simulated run, at a logical `time` in simulator ticks.
+///
+/// Sends and deliveries cover every message, faulty ones included. Coin flips and decisions
+/// are only recorded for honest processes, the inputs and outputs [`replay`] checks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// An honest process started with `initial_value`.
+    Start {
+        time: u64,
+        node: usize,
+        initial_value: bool,
+    },
+    Send {
+        time: u64,
+        from: usize,
+        to: usize,
+        message: Message<bool>,
+    },
+    Deliver {
+        time: u64,
+        from: usize,
+        to: usize,
+        message: Message<bool>,
+    },
+    /// `node` got `value` for the coin flip ending `phase`.
+    Coin {
+        time: u64,
+        node: usize,
+        phase: usize,
+        value: bool,
+    },
+    Decide {
+        time: u64,
+        node: usize,
+        phase: usize,
+        value: bool,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Header {
+    process_count: usize,
+}
+
+/// Events of a run, in the order they happened.
+///
+/// Stored as JSON Lines: a header line giving the process count, then an event per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    pub process_count: usize,
+    pub events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    pub fn new(process_count: usize) -> Trace {
+        Trace {
+            process_count,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let header = Header {
+            process_count: self.process_count,
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writeln!(writer)?;
+        for event in &self.events {
+            serde_json::to_writer(&mut writer, event)?;
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+
+    pub fn read_from(reader: impl BufRead) -> Result<Trace, String> {
+        let read_error = |error: io::Error| format!("Cannot read the trace: {error}");
+        let mut lines = reader.lines();
+        let header = lines.next().ok_or("Expected a header line")?;
+        let header: Header = serde_json::from_str(&header.map_err(read_error)?)
+            .map_err(|error| format!("Invalid header line: {error}"))?;
+
+        let mut trace = Trace::new(header.process_count);
+        for (index, line) in lines.enumerate() {
+            let line = line.map_err(read_error)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = serde_json::from_str(&line)
+                .map_err(|error| format!("Invalid event on line {}: {error}", index + 2))?;
+            trace.events.push(event);
+        }
+        trace.validate()?;
+        Ok(trace)
+    }
+
+    /// Checks that there is a process and that every event names processes among them, as
+    /// traces read from files may not.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.process_count == 0 {
+            return Err("Expected at least one process".to_string());
+        }
+        for (index, event) in self.events.iter().enumerate() {
+            let ids = match event {
+                TraceEvent::Start { node, .. }
+                | TraceEvent::Coin { node, .. }
+                | TraceEvent::Decide { node, .. } => vec![*node],
+                TraceEvent::Send { from, to, .. } | TraceEvent::Deliver { from, to, .. } => {
+                    vec![*from, *to]
+                }
+            };
+            if let Some(id) = ids.into_iter().find(|id| *id >= self.process_count) {
+                return Err(format!(
+                    "Unknown process {id} in event {index}, expected fewer than {}",
+                    self.process_count
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Feeds the deliveries and coin flips of `trace` to a fresh [`ConsensusNode`] per honest
+/// process, and returns the nodes once every event has been replayed.
+///
+/// Fails if the trace is not valid, see [`Trace::validate`], or if a replayed node sends or
+/// decides something the trace does not record for it: every broadcast of an honest process is
+/// recorded as a send to itself among others, and those must come in the same order.
+pub fn replay(trace: &Trace) -> Result<BTreeMap<usize, ConsensusNode<bool>>, String> {
+    trace.validate()?;
+    let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(trace.process_count));
+    let mut nodes = BTreeMap::new();
+    let mut expected_sends: BTreeMap<usize, Vec<&Message<bool>>> = BTreeMap::new();
+    let mut expected_decisions = BTreeMap::new();
+    let mut sent: BTreeMap<usize, Vec<Message<bool>>> = BTreeMap::new();
+    let mut decided = BTreeMap::new();
+
+    for event in &trace.events {
+        let (node, step) = match event {
+            TraceEvent::Start {
+                node,
+                initial_value,
+                ..
+            } => {
+                let consensus = ConsensusNode::new(*node, *initial_value, quorum.clone());
+                let step = consensus.start();
+                nodes.insert(*node, consensus);
+                (*node, step)
+            }
+            TraceEvent::Send {
+                from, to, message, ..
+            } => {
+                if from == to {
+                    expected_sends.entry(*from).or_default().push(message);
+                }
+                continue;
+            }
+            TraceEvent::Deliver {
+                from, to, message, ..
+            } => match nodes.get_mut(to) {
+                Some(consensus) => (*to, consensus.handle_message(*from, message.clone())),
+                None => continue,
+            },
+            TraceEvent::Coin { node, value, .. } => {
+                let consensus = nodes
+                    .get_mut(node)
+                    .ok_or(format!("Coin flip for unknown process {node}"))?;
+                if !consensus.state().awaiting_coin() {
+                    return Err(format!("Process {node} got a coin flip it did not ask for"));
+                }
+                (*node, consensus.provide_coin(*value))
+            }
+            TraceEvent::Decide {
+                node, phase, value, ..
+            } => {
+                expected_decisions.insert(*node, (*value, *phase));
+                continue;
+            }
+        };
+        record(node, step, &nodes, &mut sent, &mut decided);
+    }
+
+    for (node, messages) in &sent {
+        let expected = expected_sends.remove(node).unwrap_or_default();
+        if let Some(index) = (0..messages.len().max(expected.len()))
+            .find(|index| messages.get(*index) != expected.get(*index).copied())
+        {
+            return Err(format!(
+                "Process {node} diverged at its message {index}: replayed {:?}, recorded {:?}",
+                messages.get(index),
+                expected.get(index)
+            ));
+        }
+    }
+    if decided != expected_decisions {
+        return Err(format!(
+            "Replayed decisions {decided:?} differ from the recorded {expected_decisions:?}"
+        ));
+    }
+    Ok(nodes)
+}
+
+fn record(
+    node: usize,
+    step: Step<bool>,
+    nodes: &BTreeMap<usize, ConsensusNode<bool>>,
+    sent: &mut BTreeMap<usize, Vec<Message<bool>>>,
+    decided: &mut BTreeMap<usize, (bool, usize)>,
+) {
+    sent.entry(node).or_default().extend(step.messages);
+    if let Some(value) = step.decided {
+        decided.insert(node, (value, nodes[&node].state().phase()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::simulation::{
+        self, Coin, InitialValues, NetworkConditions, Runtime, SimulationConfig,
+    };
+
+    /// Simulates 4 processes split between both values, recording what happens.
+    fn traced_run() -> (Trace, Vec<ConsensusNode<bool>>) {
+        let config = SimulationConfig {
+            process_count: 4,
+            faults: Vec::new(),
+            initial_values: InitialValues::Split,
+            coin: Coin::Local,
+            runtime: Runtime::Simulator,
+            seed: 0,
+            timeout: Duration::from_secs(30),
+            network: NetworkConditions::default(),
+        };
+        let simulated = simulation::simulate(&config, config.initial_values(), true);
+        (simulated.trace.unwrap(), simulated.nodes)
+    }
+
+    #[test]
+    fn replay_reproduces_the_recorded_states() {
+        let (trace, nodes) = traced_run();
+        let mut file = Vec::new();
+        trace.write_to(&mut file).unwrap();
+        let trace = Trace::read_from(file.as_slice()).unwrap();
+
+        let replayed = replay(&trace).unwrap();
+        assert_eq!(replayed.len(), 4);
+        for node in &nodes {
+            assert!(node.terminated());
+            assert_eq!(replayed[&node.id()].state(), node.state());
+        }
+    }
+
+    #[test]
+    fn replay_reports_divergence() {
+        let (mut trace, _) = traced_run();
+        for event in &mut trace.events {
+            if let TraceEvent::Decide { value, .. } = event {
+                *value = !*value;
+            }
+        }
+        assert!(replay(&trace).unwrap_err().contains("decisions"));
+
+        let (mut trace, _) = traced_run();
+        trace.events.retain(|event| {
+            !matches!(event, TraceEvent::Deliver { to: 0, message, .. } if message.round == 0)
+        });
+        assert!(replay(&trace).is_err());
+    }
+
+    #[test]
+    fn traces_naming_unknown_processes_are_rejected() {
+        let read = |text: &str| Trace::read_from(text.as_bytes());
+        assert!(read("{\"process_count\":0}\n").is_err());
+
+        let start = r#"{"event":"start","time":0,"node":4,"initial_value":true}"#;
+        let error = read(&format!("{{\"process_count\":4}}\n{start}\n")).unwrap_err();
+        assert!(error.contains("Unknown process 4"), "{error}");
+
+        let (mut trace, _) = traced_run();
+        if let Some(TraceEvent::Deliver { from, .. }) = trace
+            .events
+            .iter_mut()
+            .find(|event| matches!(event, TraceEvent::Deliver { .. }))
+        {
+            *from = 9;
+        }
+        assert!(replay(&trace).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::{convert::Infallible, error::Error, future::Future, time::Duration};
+
+use tokio::{
+    sync::{mpsc, Semaphore},
+    time,
+};
+
+use crate::{messaging::Message, network::ChannelConfig};
+
+/// Asynchronous way for a process to reach every process of the cluster, itself included.
+pub trait Transport<T> {
+    type Error: Error + Send + Sync + 'static;
+
+    /// Sends `message` to every process.
+    fn broadcast(
+        &mut self,
+        message: Message<T>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Next message received, with the id of the process the transport vouches it came from,
+    /// whatever sender the message names. `None` once nothing more can be received.
+    fn recv(
+        &mut self,
+    ) -> impl Future<Output = Result<Option<(usize, Message<T>)>, Self::Error>> + Send;
+}
+
+/// In-memory transport between tasks of one runtime, see [`local_cluster`]. Since every process
+/// also sends to itself, its queue stays open for as long as it exists. Messages are queued with
+/// the id of the transport that sent them.
+#[derive(Debug)]
+pub struct ChannelTransport<T> {
+    id: usize,
+    senders: Vec<mpsc::Sender<(usize, Message<T>)>>,
+    inbox: mpsc::Receiver<(usize, Message<T>)>,
+    send_timeout: Duration,
+}
+
+impl<T> ChannelTransport<T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl<T> Transport<T> for ChannelTransport<T>
+where
+    T: Clone + Send + Sync,
+{
+    type Error = Infallible;
+
+    async fn broadcast(&mut self, message: Message<T>) -> Result<(), Infallible> {
+        for sender in &self.senders {
+            // Stopped processes and queues that stay full lose the message, as in `network`
+            let sent = sender.send((self.id, message.clone()));
+            let _ = time::timeout(self.send_timeout, sent).await;
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<(usize, Message<T>)>, Infallible> {
+        Ok(self.inbox.recv().await)
+    }
+}
+
+/// Encoded message sent to a single process, as claimed to come from process `from`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub from: usize,
+    pub payload: Vec<u8>,
+    /// Authentication tag of the payload, empty on links that need none.
+    pub tag: Vec<u8>,
+}
+
+/// Unreliable way for a process to send frames to single processes, which transports such as
+/// [`AuthenticatedTransport`](crate::auth::AuthenticatedTransport) build on. Nothing vouches
+/// for the sender a frame claims.
+pub trait Link {
+    type Error: Error + Send + Sync + 'static;
+
+    fn process_count(&self) -> usize;
+
+    /// Sends `frame` to process `to`.
+    fn send(
+        &mut self,
+        to: usize,
+        frame: Frame,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Next frame received, or `None` once nothing more can be received.
+    fn recv(&mut self) -> impl Future<Output = Result<Option<Frame>, Self::Error>> + Send;
+}
+
+/// In-memory [`Link`] between tasks of one runtime, see [`local_links`].
+#[derive(Debug)]
+pub struct ChannelLink {
+    senders: Vec<mpsc::Sender<Frame>>,
+    inbox: mpsc::Receiver<Frame>,
+    send_timeout: Duration,
+}
+
+impl Link for ChannelLink {
+    type Error = Infallible;
+
+    fn process_count(&self) -> usize {
+        self.senders.len()
+    }
+
+    async fn send(&mut self, to: usize, frame: Frame) -> Result<(), Infallible> {
+        // Lost like the messages of `ChannelTransport`
+        let _ = time::timeout(self.send_timeout, self.senders[to].send(frame)).await;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<Frame>, Infallible> {
+        Ok(self.inbox.recv().await)
+    }
+}
+
+/// Connects `process_count` tasks to each other, returning their links by id, with queues sized
+/// as by [`local_cluster`]. Fails if the configured capacity is 0.
+pub fn local_links(
+    process_count: usize,
+    config: ChannelConfig,
+) -> Result<Vec<ChannelLink>, String> {
+    let capacity = queue_capacity(process_count, config)?;
+    let (senders, inboxes): (Vec<_>, Vec<_>) =
+        (0..process_count).map(|_| mpsc::channel(capacity)).unzip();
+    Ok(inboxes
+        .into_iter()
+        .map(|inbox| ChannelLink {
+            senders: senders.clone(),
+            inbox,
+            send_timeout: config.send_timeout,
+        })
+        .collect())
+}
+
+/// Room in the queue a process shares between its peers, as much as tokio allows at most.
+fn queue_capacity(process_count: usize, config: ChannelConfig) -> Result<usize, String> {
+    let Some(capacity) = config.capacity else {
+        return Ok(Semaphore::MAX_PERMITS);
+    };
+    if capacity == 0 {
+        return Err("Expected room for at least one message per peer".to_string());
+    }
+    Ok(capacity
+        .checked_mul(process_count)
+        .map_or(Semaphore::MAX_PERMITS, |room| {
+            room.min(Semaphore::MAX_PERMITS)
+        }))
+}
+
+/// Connects `process_count` tasks to each other, returning their transports by id. Each process
+/// has a single queue shared by its peers, with room for the configured capacity of each. Fails
+/// if that capacity is 0.
+pub fn local_cluster<T>(
+    process_count: usize,
+    config: ChannelConfig,
+) -> Result<Vec<ChannelTransport<T>>, String> {
+    let capacity = queue_capacity(process_count, config)?;
+    let (senders, inboxes): (Vec<_>, Vec<_>) =
+        (0..process_count).map(|_| mpsc::channel(capacity)).unzip();
+    Ok(inboxes
+        .into_iter()
+        .enumerate()
+        .map(|(id, inbox)| ChannelTransport {
+            id,
+            senders: senders.clone(),
+            inbox,
+            send_timeout: config.send_timeout,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_capacities_are_checked() {
+        let config = |capacity| ChannelConfig {
+            capacity: Some(capacity),
+            ..ChannelConfig::unbounded()
+        };
+        assert!(local_cluster::<bool>(4, config(0)).is_err());
+        assert!(local_links(4, config(0)).is_err());
+        // Clamped to what tokio allows rather than overflowing
+        assert_eq!(
+            queue_capacity(4, config(usize::MAX)),
+            Ok(Semaphore::MAX_PERMITS)
+        );
+        assert_eq!(local_links(4, config(usize::MAX)).unwrap().len(), 4);
+    }
+}
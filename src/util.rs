@@ -1,36 +1,122 @@
-use std::{hash, fmt::Debug};
+use std::{
+    fmt::Debug,
+    hash,
+    sync::{Arc, Mutex},
+};
 
-use crossbeam::channel::{Receiver, Sender};
+use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
 
-use crate::messaging::Message;
+use crate::{
+    metrics::MetricsSink,
+    network::{Inbox, PeerSender},
+    quorum::{QuorumSystem, ThresholdQuorum, WeightedQuorum},
+};
 
-pub trait Broadcastable: Clone + Eq + Ord + hash::Hash + Send + Debug+ 'static {}
+pub trait Broadcastable: Clone + Eq + Ord + hash::Hash + Send + Debug + 'static {}
+
+impl<T> Broadcastable for T where T: Clone + Eq + Ord + hash::Hash + Send + Debug + 'static {}
 
 pub struct NetworkInfo<T> {
     pub id: usize,
-    pub senders: Vec<Sender<Message<T>>>,
-    pub receiver: Receiver<Message<T>>,
+    pub senders: Vec<PeerSender<T>>,
+    pub inbox: Inbox<T>,
+    pub quorum: Arc<dyn QuorumSystem>,
+    pub cancel: CancellationToken,
+    pub metrics: Option<Arc<dyn MetricsSink>>,
 }
 
 impl<T> NetworkInfo<T> {
-    pub fn new(
+    pub fn new(id: usize, senders: Vec<PeerSender<T>>, inbox: Inbox<T>) -> NetworkInfo<T> {
+        let quorum = Arc::new(ThresholdQuorum::new(senders.len()));
+        NetworkInfo::with_quorum(id, senders, inbox, quorum)
+    }
+
+    pub fn weighted(
+        id: usize,
+        senders: Vec<PeerSender<T>>,
+        inbox: Inbox<T>,
+        weights: Vec<u64>,
+    ) -> NetworkInfo<T> {
+        let quorum = Arc::new(WeightedQuorum::new(weights));
+        NetworkInfo::with_quorum(id, senders, inbox, quorum)
+    }
+
+    pub fn with_quorum(
         id: usize,
-        senders: Vec<Sender<Message<T>>>,
-        receiver: Receiver<Message<T>>,
+        senders: Vec<PeerSender<T>>,
+        inbox: Inbox<T>,
+        quorum: Arc<dyn QuorumSystem>,
     ) -> NetworkInfo<T> {
+        assert_eq!(
+            senders.len(),
+            quorum.process_count(),
+            "Expected the quorum system to cover every process"
+        );
         NetworkInfo {
             id,
             senders,
-            receiver,
+            inbox,
+            quorum,
+            cancel: CancellationToken::new(),
+            metrics: None,
+        }
+    }
+
+    /// Makes the process stop once `cancel` is cancelled.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> NetworkInfo<T> {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Makes the process report to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> NetworkInfo<T> {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+/// Shared flag telling worker loops to stop. Cancelling disconnects a channel, so that loops
+/// blocked on a receive wake up too.
+#[derive(Clone, Debug)]
+pub struct CancellationToken {
+    trigger: Arc<Mutex<Option<Sender<()>>>>,
+    cancelled: Receiver<()>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        let (trigger, cancelled) = channel::bounded(0);
+        CancellationToken {
+            trigger: Arc::new(Mutex::new(Some(trigger))),
+            cancelled,
         }
     }
+
+    pub fn cancel(&self) {
+        self.trigger.lock().unwrap().take();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.cancelled.try_recv(), Err(TryRecvError::Disconnected))
+    }
+
+    /// Channel that disconnects once cancelled, to wait on next to other channels.
+    pub(crate) fn receiver(&self) -> &Receiver<()> {
+        &self.cancelled
+    }
 }
 
 //TODO avoid recalculating
 pub fn faulty_count(process_count: usize) -> usize {
     // faulty count < process_count / 3
-    if process_count % 3 == 0 {
-        process_count / 3 - 1
+    if process_count.is_multiple_of(3) {
+        (process_count / 3).saturating_sub(1)
     } else {
         process_count / 3
     }
@@ -1,12 +1,20 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet};
 
-use crate::{
-    broadcast::BroadcastValue,
-    util::{self, Broadcastable},
-};
+use crate::{broadcast::BroadcastValue, quorum::QuorumSystem, util::Broadcastable};
 
+/// Values accepted in one round, with the distinct processes whose broadcast carried each.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ValidatedMessageSet<T> {
-    messages: HashMap<T, usize>,
+    messages: BTreeMap<BroadcastValue<T>, BTreeSet<usize>>,
+}
+
+impl<T> Default for ValidatedMessageSet<T>
+where
+    T: Broadcastable,
+{
+    fn default() -> Self {
+        ValidatedMessageSet::new()
+    }
 }
 
 impl<T> ValidatedMessageSet<T>
@@ -15,55 +23,170 @@ where
 {
     pub fn new() -> ValidatedMessageSet<T> {
         ValidatedMessageSet {
-            messages: HashMap::new(),
+            messages: BTreeMap::new(),
         }
     }
 
-    pub fn add(&mut self, value: BroadcastValue<T>) {
-        *self.messages.entry(value.value).or_insert(0) += 1;
+    pub fn add(&mut self, broadcast_source_id: usize, value: BroadcastValue<T>) {
+        self.messages
+            .entry(value)
+            .or_default()
+            .insert(broadcast_source_id);
+    }
+
+    /// Every process with a validated value in this set.
+    pub fn senders(&self) -> BTreeSet<usize> {
+        self.messages.values().flatten().copied().collect()
     }
 
-    //TODO connect with selection_protocol
-    pub fn validate(&self, round: usize, process_count: usize, value: &BroadcastValue<T>) -> bool {
-        if self.messages.len() == 0 {
-            return true;
+    /// Whether enough values have been validated for the round to end.
+    pub fn is_complete(&self, quorum: &dyn QuorumSystem) -> bool {
+        quorum.is_quorum(&self.senders())
+    }
+
+    /// Whether a correct process could have broadcast `value` in `round` after validating some
+    /// quorum of the values in this set, which holds the previous round's values.
+    ///
+    /// The answer only changes from `false` to `true` as the set grows, so values that cannot
+    /// be validated yet should be kept and checked again later.
+    pub fn validate(
+        &self,
+        round: usize,
+        quorum: &dyn QuorumSystem,
+        value: &BroadcastValue<T>,
+    ) -> bool {
+        if !self.is_complete(quorum) {
+            return false;
         }
 
-        let faulty_count = util::faulty_count(process_count);
         match round % 3 {
-            0 => self.validate_threshold(value, (process_count - faulty_count) / 2),
-            1 => self.validate_threshold(value, process_count / 2),
+            // Adopted from a blocking set of decided values, or the outcome of a coin flip
+            0 => {
+                !value.decided
+                    && (quorum.is_blocking(&self.senders_of(&value.value, false))
+                        || self.allows_coin(quorum))
+            }
+            // The value held by the most voting power within some quorum
+            1 => !value.decided && self.allows_plurality(quorum, &value.value),
+            // Decided once a majority holds the value, otherwise any value may be kept
             2 => {
                 if value.decided {
-                    self.validate_threshold(value, 2 * faulty_count)
+                    quorum.is_majority(&self.senders_of(&value.value, true))
                 } else {
-                    self.validate_threshold(value, faulty_count)
+                    self.allows_no_majority(quorum)
                 }
             }
             _ => unreachable!(),
         }
     }
 
-    fn validate_threshold(&self, value: &BroadcastValue<T>, threshold: usize) -> bool {
-        self.messages.contains_key(&value.value)
-            && *self.messages.get(&value.value).unwrap() > threshold
+    /// Processes whose validated value is `value`, including undecided ones if `include_undecided`.
+    fn senders_of(&self, value: &T, include_undecided: bool) -> BTreeSet<usize> {
+        self.messages
+            .iter()
+            .filter(|(candidate, _)| candidate.value == *value)
+            .filter(|(candidate, _)| include_undecided || candidate.decided)
+            .flat_map(|(_, senders)| senders.iter().copied())
+            .collect()
     }
 
-    pub fn get_threshold_majority(&self, threshold: usize, include_undecided: bool) -> Option<T> {
-        self.messages
+    /// Validated values with their distinct senders, merging decided and undecided copies if
+    /// `include_undecided` and ignoring undecided ones otherwise.
+    fn supporters(&self, include_undecided: bool) -> BTreeMap<T, BTreeSet<usize>> {
+        let mut supporters: BTreeMap<T, BTreeSet<usize>> = BTreeMap::new();
+        for (value, senders) in &self.messages {
+            if include_undecided || value.decided {
+                supporters
+                    .entry(value.value.clone())
+                    .or_default()
+                    .extend(senders);
+            }
+        }
+        supporters
+    }
+
+    /// Some quorum exists in which no value is decided by a blocking set.
+    fn allows_coin(&self, quorum: &dyn QuorumSystem) -> bool {
+        let mut witness: BTreeSet<usize> = self
+            .messages
             .iter()
-            .fold(
-                (None, threshold), //If no elements have at least threshold messages then return None
-                |(current_value, threshold), (new_value, count)| {
-                    // Keep the element with the larger count
-                    if *count > threshold {
-                        (Some(new_value.clone()), *count)
-                    } else {
-                        (current_value, threshold)
-                    }
-                },
-            )
-            .0
-            .clone()
+            .filter(|(value, _)| !value.decided)
+            .flat_map(|(_, senders)| senders.iter().copied())
+            .collect();
+        for senders in self.supporters(false).values() {
+            witness.extend(largest_subset(quorum, senders, |part| {
+                !quorum.is_blocking(part)
+            }));
+        }
+        quorum.is_quorum(&witness)
+    }
+
+    /// Some quorum exists in which `value` is the one selected by the plurality rule.
+    fn allows_plurality(&self, quorum: &dyn QuorumSystem, value: &T) -> bool {
+        let supporters = self.supporters(true);
+        let own = match supporters.get(value) {
+            Some(own) => own,
+            None => return false,
+        };
+        let power = quorum.voting_power(own);
+        let mut witness = own.clone();
+        for (other, senders) in &supporters {
+            // Ties go to the larger value
+            if other > value {
+                witness.extend(largest_subset(quorum, senders, |part| {
+                    quorum.voting_power(part) < power
+                }));
+            } else if other < value {
+                witness.extend(largest_subset(quorum, senders, |part| {
+                    quorum.voting_power(part) <= power
+                }));
+            }
+        }
+        quorum.is_quorum(&witness)
+    }
+
+    /// Some quorum exists in which no value is held by a majority.
+    fn allows_no_majority(&self, quorum: &dyn QuorumSystem) -> bool {
+        let mut witness = BTreeSet::new();
+        for senders in self.supporters(true).values() {
+            witness.extend(largest_subset(quorum, senders, |part| {
+                !quorum.is_majority(part)
+            }));
+        }
+        quorum.is_quorum(&witness)
+    }
+
+    /// Value whose distinct senders satisfy `supported`, preferring the one with the most voting
+    /// power.
+    pub fn get_supported_majority(
+        &self,
+        quorum: &dyn QuorumSystem,
+        include_undecided: bool,
+        supported: impl Fn(&BTreeSet<usize>) -> bool,
+    ) -> Option<T> {
+        self.supporters(include_undecided)
+            .into_iter()
+            .filter(|(_, senders)| supported(senders))
+            .max_by_key(|(value, senders)| (quorum.voting_power(senders), value.clone()))
+            .map(|(value, _)| value)
+    }
+}
+
+/// Greedily picks as many of `senders` as `allowed` accepts, lightest first.
+fn largest_subset(
+    quorum: &dyn QuorumSystem,
+    senders: &BTreeSet<usize>,
+    allowed: impl Fn(&BTreeSet<usize>) -> bool,
+) -> BTreeSet<usize> {
+    let mut ordered: Vec<_> = senders.iter().copied().collect();
+    ordered.sort_by_key(|id| quorum.voting_power(&BTreeSet::from([*id])));
+
+    let mut subset = BTreeSet::new();
+    for id in ordered {
+        subset.insert(id);
+        if !allowed(&subset) {
+            subset.remove(&id);
+        }
     }
+    subset
 }
@@ -0,0 +1,364 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use async_byz_consensus::{
+    acs::CommonSubset,
+    broadcast::BroadcastValue,
+    byz_protocol,
+    cluster::ClusterHandle,
+    faulty,
+    network::{self, ChannelConfig},
+    quorum::{QuorumSystem, ThresholdQuorum},
+    replicated_log::ReplicatedLog,
+    simulation::{self, Coin, Fault, InitialValues, NetworkConditions, Runtime, SimulationConfig},
+    util::{self, NetworkInfo},
+};
+use proptest::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Time every honest process gets to decide in a threaded run.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Time the threads get to stop once a threaded run is over.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+/// Phases within which every honest process is expected to decide in a simulated run.
+const MAX_PHASES: usize = 12;
+
+const VALUES: [BroadcastValue<bool>; 4] = [
+    BroadcastValue {
+        value: false,
+        decided: false,
+    },
+    BroadcastValue {
+        value: true,
+        decided: false,
+    },
+    BroadcastValue {
+        value: false,
+        decided: true,
+    },
+    BroadcastValue {
+        value: true,
+        decided: true,
+    },
+];
+
+#[derive(Clone, Debug)]
+enum Byzantine {
+    /// Faulty processes never send anything.
+    Silent,
+    /// Faulty processes send the same value for every broadcast, see `faulty::faulty_process`.
+    Repeat(BroadcastValue<bool>),
+    /// Faulty processes send random values, different for every recipient.
+    Equivocate,
+}
+
+/// Honest processes come first and start with `initial_values`, the other ones are faulty.
+#[derive(Clone, Debug)]
+struct Scenario {
+    process_count: usize,
+    initial_values: Vec<bool>,
+    byzantine: Byzantine,
+    seed: u64,
+}
+
+impl Scenario {
+    fn unanimous(&self) -> Option<bool> {
+        let values: BTreeSet<_> = self.initial_values.iter().copied().collect();
+        (values.len() == 1).then(|| self.initial_values[0])
+    }
+}
+
+fn byzantine() -> impl Strategy<Value = Byzantine> {
+    prop_oneof![
+        Just(Byzantine::Silent),
+        (0..VALUES.len()).prop_map(|index| Byzantine::Repeat(VALUES[index].clone())),
+        Just(Byzantine::Equivocate),
+    ]
+}
+
+fn scenario(process_counts: std::ops::RangeInclusive<usize>) -> impl Strategy<Value = Scenario> {
+    process_counts
+        .prop_flat_map(|process_count| (Just(process_count), 0..=util::faulty_count(process_count)))
+        .prop_flat_map(|(process_count, faulty_count)| {
+            (
+                Just(process_count),
+                prop::collection::vec(any::<bool>(), process_count - faulty_count),
+                byzantine(),
+                any::<u64>(),
+            )
+        })
+        .prop_map(
+            |(process_count, initial_values, byzantine, seed)| Scenario {
+                process_count,
+                initial_values,
+                byzantine,
+                seed,
+            },
+        )
+}
+
+thread_local! {
+    static COIN: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(0));
+}
+
+fn seeded_coin() -> bool {
+    COIN.with(|rng| rng.borrow_mut().gen_bool(0.5))
+}
+
+/// Runs `consensus_protocol` on a thread per honest process and returns what each of them
+/// decided within `TIMEOUT`.
+fn run_threads(scenario: &Scenario) -> Vec<Option<bool>> {
+    let honest_count = scenario.initial_values.len();
+    let endpoints = network::connect(
+        scenario.process_count,
+        ChannelConfig::for_cluster(scenario.process_count),
+    );
+    let (results, decisions) = mpsc::channel();
+    let mut cluster = ClusterHandle::new();
+
+    for (id, (senders, inbox)) in endpoints.into_iter().enumerate() {
+        let network = NetworkInfo::new(id, senders, inbox).with_cancellation(cluster.token());
+        let seed = scenario.seed.wrapping_add(id as u64);
+        if id < honest_count {
+            let initial_value = scenario.initial_values[id];
+            let results = results.clone();
+            cluster.spawn(id, move || {
+                COIN.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+                let value = byz_protocol::consensus_protocol(initial_value, seeded_coin, network)?;
+                let _ = results.send((id, value));
+                Some(value)
+            });
+            continue;
+        }
+        match scenario.byzantine.clone() {
+            Byzantine::Silent => {}
+            Byzantine::Repeat(value) => cluster.spawn(id, move || {
+                faulty::faulty_process(value, network);
+                None
+            }),
+            Byzantine::Equivocate => cluster.spawn(id, move || {
+                faulty::equivocating_process(VALUES.to_vec(), seed, network);
+                None
+            }),
+        }
+    }
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut decided = vec![None; honest_count];
+    for _ in 0..honest_count {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match decisions.recv_timeout(remaining) {
+            Ok((id, value)) => decided[id] = Some(value),
+            Err(_) => break,
+        }
+    }
+
+    let report = cluster.shutdown(SHUTDOWN_DEADLINE);
+    assert!(
+        report.is_clean(),
+        "Expected every thread to stop: {report:?}"
+    );
+    decided
+}
+
+/// Runs every process in the simulator, with delays and coin flips drawn from the scenario's
+/// seed, until the honest ones terminate, and returns the value each of them decided with the
+/// phase it had reached by then.
+fn simulate(scenario: &Scenario) -> Vec<Option<(bool, usize)>> {
+    let fault = match &scenario.byzantine {
+        Byzantine::Silent => Fault::Silent,
+        Byzantine::Repeat(value) => Fault::Repeat(value.clone()),
+        Byzantine::Equivocate => Fault::Equivocate,
+    };
+    let config = SimulationConfig {
+        process_count: scenario.process_count,
+        faults: vec![fault; scenario.process_count - scenario.initial_values.len()],
+        initial_values: InitialValues::Random,
+        coin: Coin::Local,
+        runtime: Runtime::Simulator,
+        seed: scenario.seed,
+        timeout: TIMEOUT,
+        network: NetworkConditions::default(),
+    };
+    simulation::simulate(&config, scenario.initial_values.clone(), false)
+        .reports
+        .iter()
+        .filter(|report| report.is_honest())
+        .map(|report| report.decided.zip(report.phase))
+        .collect()
+}
+
+/// Runs a replicated log at each honest process, each proposing `commands` values of its own,
+/// with faulty processes staying silent. Messages are delivered in an order drawn from the seed
+/// until none is left, and the log delivered by each process is returned.
+fn simulate_logs(scenario: &Scenario, window: usize, commands: usize) -> Vec<Vec<(usize, usize)>> {
+    let process_count = scenario.process_count;
+    let honest_count = scenario.initial_values.len();
+    let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(process_count));
+    let mut rng = StdRng::seed_from_u64(scenario.seed);
+
+    let delivered: Vec<_> = (0..honest_count)
+        .map(|_| RefCell::new(Vec::new()))
+        .collect();
+    let mut logs: Vec<_> = delivered
+        .iter()
+        .enumerate()
+        .map(|(id, sink)| {
+            ReplicatedLog::new(id, quorum.clone(), window, move |slot, value| {
+                sink.borrow_mut().push((slot, value))
+            })
+        })
+        .collect();
+    let mut in_flight = Vec::new();
+    for (id, log) in logs.iter_mut().enumerate() {
+        for command in 0..commands {
+            let messages = log.propose(id * commands + command).messages;
+            in_flight.extend(
+                messages
+                    .into_iter()
+                    .flat_map(|message| (0..honest_count).map(move |to| (to, message.clone()))),
+            );
+        }
+    }
+
+    while !in_flight.is_empty() {
+        let (to, message) = in_flight.swap_remove(rng.gen_range(0..in_flight.len()));
+        let log = &mut logs[to];
+        let mut step = log.handle_message(message.sender_id, message);
+        while let Some(slot) = step.coin_requests.pop() {
+            let coin = log.proposal(slot).copied().filter(|_| rng.gen_bool(0.5));
+            let next = log.provide_coin(slot, coin);
+            step.messages.extend(next.messages);
+            step.coin_requests.extend(next.coin_requests);
+        }
+        for message in step.messages {
+            in_flight.extend((0..honest_count).map(|to| (to, message.clone())));
+        }
+    }
+
+    drop(logs);
+    delivered.into_iter().map(RefCell::into_inner).collect()
+}
+
+/// Runs a common subset at each honest process, each proposing its id, with faulty processes
+/// staying silent. Messages are delivered in an order drawn from the seed until none is left,
+/// and the output of each process is returned.
+fn simulate_common_subsets(scenario: &Scenario) -> Vec<Option<BTreeMap<usize, usize>>> {
+    let honest_count = scenario.initial_values.len();
+    let quorum: Arc<dyn QuorumSystem> = Arc::new(ThresholdQuorum::new(scenario.process_count));
+    let mut rng = StdRng::seed_from_u64(scenario.seed);
+
+    let mut subsets: Vec<_> = (0..honest_count)
+        .map(|id| CommonSubset::new(id, quorum.clone()))
+        .collect();
+    let mut in_flight = Vec::new();
+    for subset in &subsets {
+        for message in subset.propose(subset.id()).messages {
+            in_flight.extend((0..honest_count).map(|to| (to, message.clone())));
+        }
+    }
+    let mut outputs = vec![None; honest_count];
+
+    while !in_flight.is_empty() {
+        let (to, message) = in_flight.swap_remove(rng.gen_range(0..in_flight.len()));
+        let subset = &mut subsets[to];
+        let mut step = subset.handle_message(message.sender_id(), message);
+        while let Some(slot) = step.coin_requests.pop() {
+            let next = subset.provide_coin(slot, rng.gen_bool(0.5));
+            step.messages.extend(next.messages);
+            step.coin_requests.extend(next.coin_requests);
+            step.output = step.output.or(next.output);
+        }
+        if step.output.is_some() {
+            outputs[to] = step.output;
+        }
+        for message in step.messages {
+            in_flight.extend((0..honest_count).map(|to| (to, message.clone())));
+        }
+    }
+
+    outputs
+}
+
+/// Checks that the processes that decided agree, on the initial value if it was unanimous.
+fn check_decisions(scenario: &Scenario, decided: &[Option<bool>]) -> Result<(), TestCaseError> {
+    let values: BTreeSet<_> = decided.iter().flatten().collect();
+    prop_assert!(values.len() <= 1, "Expected a single decision: {decided:?}");
+    if let Some(value) = scenario.unanimous() {
+        prop_assert!(
+            values.iter().all(|decision| **decision == value),
+            "Expected unanimous {value} to be decided: {decided:?}"
+        );
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(24))]
+
+    #[test]
+    fn honest_processes_agree(scenario in scenario(4..=10)) {
+        check_decisions(&scenario, &run_threads(&scenario))?;
+    }
+
+    #[test]
+    fn honest_processes_terminate(scenario in scenario(4..=10)) {
+        let decided = run_threads(&scenario);
+        prop_assert!(decided.iter().all(Option::is_some), "Expected every honest process to decide: {decided:?}");
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(200))]
+
+    #[test]
+    fn simulated_runs_agree(scenario in scenario(4..=13)) {
+        let decided: Vec<_> = simulate(&scenario).iter().map(|decision| decision.map(|(value, _)| value)).collect();
+        check_decisions(&scenario, &decided)?;
+    }
+
+    #[test]
+    fn simulated_runs_terminate_within_bounded_phases(scenario in scenario(4..=13)) {
+        let decided = simulate(&scenario);
+        prop_assert!(decided.iter().all(Option::is_some), "Expected every honest process to decide: {decided:?}");
+        let phases = decided.iter().flatten().map(|(_, phase)| *phase).max().unwrap();
+        prop_assert!(phases < MAX_PHASES, "Expected a decision within {MAX_PHASES} phases: {decided:?}");
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(48))]
+
+    #[test]
+    fn simulated_logs_agree_and_deliver_every_value(
+        scenario in scenario(4..=7),
+        window in 1..=3usize,
+        commands in 0..=3usize,
+    ) {
+        let logs = simulate_logs(&scenario, window, commands);
+        for log in &logs {
+            prop_assert_eq!(log, &logs[0]);
+        }
+        let mut values: Vec<_> = logs[0].iter().map(|(_, value)| *value).collect();
+        values.sort_unstable();
+        let expected: Vec<_> = (0..logs.len() * commands).collect();
+        prop_assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn simulated_common_subsets_agree_on_a_quorum_of_proposals(scenario in scenario(4..=7)) {
+        let quorum = ThresholdQuorum::new(scenario.process_count);
+        let outputs = simulate_common_subsets(&scenario);
+        prop_assert!(outputs.iter().all(Option::is_some), "Expected every honest process to output: {outputs:?}");
+        let output = outputs[0].clone().unwrap();
+        for other in &outputs {
+            prop_assert_eq!(other.as_ref(), Some(&output));
+        }
+        prop_assert!(output.iter().all(|(slot, value)| slot == value), "{output:?}");
+        prop_assert!(quorum.is_quorum(&output.keys().copied().collect()), "{output:?}");
+    }
+}
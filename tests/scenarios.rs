@@ -0,0 +1,28 @@
+use std::{fs, path::Path};
+
+use async_byz_consensus::{scenario::Scenario, simulation};
+
+/// Every scenario checked into `scenarios/` runs and meets its expectations.
+#[test]
+fn checked_in_scenarios_meet_their_expectations() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios");
+    let mut paths: Vec<_> = fs::read_dir(directory)
+        .expect("Expected a scenarios directory")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let scenario = Scenario::load(&path).unwrap();
+        let config = scenario.config();
+        config.validate().unwrap();
+        let report = simulation::run(&config);
+        let failures = scenario.expect.failures(&report);
+        assert!(
+            failures.is_empty(),
+            "{}: {failures:?}\n{report}",
+            path.display()
+        );
+    }
+}